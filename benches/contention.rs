@@ -0,0 +1,123 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! High contention benchmarks
+//!
+//! Each lock is measured against a copy of the SegQueue waker list it used before the WaiterQueue, see `segqueue`.
+//! To compare against another revision run `cargo bench -- --save-baseline <name>` on it,
+//! then `cargo bench -- --baseline <name>` on this one.
+
+#[path = "contention/segqueue.rs"]
+mod segqueue;
+
+use std::future::Future;
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use future_parking_lot::mutex::{FutureLockable, Mutex};
+use future_parking_lot::rwlock::{FutureReadable, FutureWriteable, RwLock};
+
+const ITERATIONS: usize = 100;
+
+/// Runs `tasks` tasks contending for the same lock, `task` gets the index of the task
+fn contend<L, F, Fut>(runtime: &Runtime, tasks: usize, lock: &Arc<L>, task: F)
+where
+    L: Send + Sync + 'static,
+    F: Fn(Arc<L>, usize) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    runtime.block_on(async {
+        let (tx, mut rx) = mpsc::channel(tasks);
+        for i in 0..tasks {
+            let work = task(Arc::clone(lock), i);
+            let mut tx = tx.clone();
+            tokio::spawn(async move {
+                work.await;
+                tx.send(()).await.ok();
+            });
+        }
+        for _ in 0..tasks {
+            rx.recv().await;
+        }
+    });
+}
+
+fn mutex(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("mutex");
+    for tasks in [10, 100, 1000].iter() {
+        group.bench_with_input(BenchmarkId::new("waiter_queue", tasks), tasks, |b, &tasks| {
+            b.iter(|| {
+                let lock = Arc::new(Mutex::new(0usize));
+                contend(&runtime, tasks, &lock, |lock, _| async move {
+                    for _ in 0..ITERATIONS {
+                        *lock.future_lock().await += 1;
+                    }
+                });
+                assert_eq!(*lock.lock(), tasks * ITERATIONS);
+            });
+        });
+        group.bench_with_input(BenchmarkId::new("segqueue", tasks), tasks, |b, &tasks| {
+            b.iter(|| {
+                let lock = Arc::new(segqueue::Mutex::new(0usize));
+                contend(&runtime, tasks, &lock, |lock, _| async move {
+                    for _ in 0..ITERATIONS {
+                        *segqueue::future_lock(&lock).await += 1;
+                    }
+                });
+                assert_eq!(*lock.lock(), tasks * ITERATIONS);
+            });
+        });
+    }
+    group.finish();
+}
+
+fn rwlock(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("rwlock");
+    for tasks in [10, 100, 1000].iter() {
+        // one writer every ten tasks
+        group.bench_with_input(BenchmarkId::new("waiter_queue", tasks), tasks, |b, &tasks| {
+            b.iter(|| {
+                let lock = Arc::new(RwLock::new(0usize));
+                contend(&runtime, tasks, &lock, |lock, i| async move {
+                    for _ in 0..ITERATIONS {
+                        if i % 10 == 0 {
+                            *lock.future_write().await += 1;
+                        }
+                        else {
+                            let _ = *lock.future_read().await;
+                        }
+                    }
+                });
+            });
+        });
+        group.bench_with_input(BenchmarkId::new("segqueue", tasks), tasks, |b, &tasks| {
+            b.iter(|| {
+                let lock = Arc::new(segqueue::RwLock::new(0usize));
+                contend(&runtime, tasks, &lock, |lock, i| async move {
+                    for _ in 0..ITERATIONS {
+                        if i % 10 == 0 {
+                            *segqueue::future_write(&lock).await += 1;
+                        }
+                        else {
+                            let _ = *segqueue::future_read(&lock).await;
+                        }
+                    }
+                });
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mutex, rwlock);
+criterion_main!(benches);
@@ -0,0 +1,181 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The waker list the WaiterQueue replaced, kept to benchmark against it
+//!
+//! A lazily allocated SegQueue of cloned Wakers, with a spinning flag making registration atomic.
+//! Unlike the original the list isn't leaked when two threads race to allocate it.
+
+use std::future::{poll_fn, Future};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::task::{Context, Poll, Waker};
+
+use crossbeam_queue::SegQueue;
+
+use lock_api::{GuardNoSend, MutexGuard, RawMutex, RawRwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// a Mutex waking its Futures from a SegQueue
+pub type Mutex<T> = lock_api::Mutex<RawSegQueueMutex, T>;
+
+/// a RwLock waking its Futures from a SegQueue
+pub type RwLock<T> = lock_api::RwLock<RawSegQueueRwLock, T>;
+
+struct Wakers {
+    locking: AtomicBool,
+    list: AtomicPtr<SegQueue<Waker>>,
+}
+
+impl Wakers {
+    const fn new() -> Wakers {
+        Wakers {
+            locking: AtomicBool::new(false),
+            list: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    // makes trying the lock and registering atomic, so that a release can't happen in between
+    fn atomic_lock(&self) {
+        while self.locking.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {}
+    }
+
+    fn atomic_unlock(&self) {
+        self.locking.store(false, Ordering::Release);
+    }
+
+    fn list(&self) -> &SegQueue<Waker> {
+        let mut list = self.list.load(Ordering::Acquire);
+        if list.is_null() {
+            let new = Box::into_raw(Box::new(SegQueue::new()));
+            list = match self.list.compare_exchange(null_mut(), new, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => new,
+                Err(current) => {
+                    drop(unsafe { Box::from_raw(new) });
+                    current
+                },
+            };
+        }
+        unsafe { &*list }
+    }
+
+    fn poll_acquire<G, F>(&self, cx: &mut Context, try_acquire: F) -> Poll<G>
+    where
+        F: FnOnce() -> Option<G>,
+    {
+        self.atomic_lock();
+        let res = match try_acquire() {
+            Some(guard) => Poll::Ready(guard),
+            None => {
+                self.list().push(cx.waker().clone());
+                Poll::Pending
+            },
+        };
+        self.atomic_unlock();
+        res
+    }
+
+    fn wake_up(&self) {
+        self.atomic_lock();
+        if let Ok(waker) = self.list().pop() {
+            waker.wake();
+        }
+        self.atomic_unlock();
+    }
+}
+
+impl Drop for Wakers {
+    fn drop(&mut self) {
+        let list = *self.list.get_mut();
+        if !list.is_null() {
+            drop(unsafe { Box::from_raw(list) });
+        }
+    }
+}
+
+/// RawMutex implementor waking its Futures from a SegQueue
+pub struct RawSegQueueMutex {
+    wakers: Wakers,
+    inner: parking_lot::RawMutex,
+}
+
+unsafe impl RawMutex for RawSegQueueMutex {
+    type GuardMarker = GuardNoSend;
+
+    const INIT: RawSegQueueMutex = RawSegQueueMutex {
+        wakers: Wakers::new(),
+        inner: parking_lot::RawMutex::INIT,
+    };
+
+    fn lock(&self) {
+        self.inner.lock();
+    }
+
+    fn try_lock(&self) -> bool {
+        self.inner.try_lock()
+    }
+
+    fn unlock(&self) {
+        self.inner.unlock();
+        self.wakers.wake_up();
+    }
+}
+
+/// RawRwLock implementor waking its Futures from a SegQueue
+pub struct RawSegQueueRwLock {
+    wakers: Wakers,
+    inner: parking_lot::RawRwLock,
+}
+
+unsafe impl RawRwLock for RawSegQueueRwLock {
+    type GuardMarker = GuardNoSend;
+
+    const INIT: RawSegQueueRwLock = RawSegQueueRwLock {
+        wakers: Wakers::new(),
+        inner: parking_lot::RawRwLock::INIT,
+    };
+
+    fn lock_shared(&self) {
+        self.inner.lock_shared();
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.inner.try_lock_shared()
+    }
+
+    fn unlock_shared(&self) {
+        self.inner.unlock_shared();
+        self.wakers.wake_up();
+    }
+
+    fn lock_exclusive(&self) {
+        self.inner.lock_exclusive();
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.inner.try_lock_exclusive()
+    }
+
+    fn unlock_exclusive(&self) {
+        self.inner.unlock_exclusive();
+        self.wakers.wake_up();
+    }
+}
+
+/// Locks the Mutex without blocking
+pub fn future_lock<T>(lock: &Mutex<T>) -> impl Future<Output = MutexGuard<'_, RawSegQueueMutex, T>> {
+    poll_fn(move |cx| unsafe { lock.raw() }.wakers.poll_acquire(cx, || lock.try_lock()))
+}
+
+/// Read-locks the RwLock without blocking
+pub fn future_read<T>(lock: &RwLock<T>) -> impl Future<Output = RwLockReadGuard<'_, RawSegQueueRwLock, T>> {
+    poll_fn(move |cx| unsafe { lock.raw() }.wakers.poll_acquire(cx, || lock.try_read()))
+}
+
+/// Write-locks the RwLock without blocking
+pub fn future_write<T>(lock: &RwLock<T>) -> impl Future<Output = RwLockWriteGuard<'_, RawSegQueueRwLock, T>> {
+    poll_fn(move |cx| unsafe { lock.raw() }.wakers.poll_acquire(cx, || lock.try_write()))
+}
@@ -0,0 +1,240 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+use std::future::Future;
+use std::task::{Poll, Context};
+use std::pin::Pin;
+
+use parking_lot::{const_mutex, Mutex};
+
+use crate::wakers::{WaiterQueue, WaitKind, WaitNode};
+
+struct BarrierState {
+    count: usize,
+    generation: usize,
+}
+
+/// A Future-compatible Barrier, to make a group of tasks rendezvous
+///
+/// Like std's Barrier it can be reused once all the tasks of a group have been released.
+pub struct Barrier {
+    n: usize,
+    state: Mutex<BarrierState>,
+    waiters: WaiterQueue,
+}
+
+impl Barrier {
+    /// Creates a new Barrier releasing groups of `n` tasks
+    pub const fn new(n: usize) -> Barrier {
+        Barrier {
+            n,
+            state: const_mutex(BarrierState {
+                count: 0,
+                generation: 0,
+            }),
+            waiters: WaiterQueue::new(),
+        }
+    }
+
+    /// Waits for all the tasks of the group to reach this point, without blocking
+    ///
+    /// A future dropped before being released leaves the group, which will wait for another task instead.
+    pub fn wait(&self) -> FutureBarrierWait<'_> {
+        FutureBarrierWait {
+            barrier: self,
+            waiter: WaitNode::new(WaitKind::Shared),
+            generation: None,
+            done: false,
+        }
+    }
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Barrier").field("n", &self.n).finish()
+    }
+}
+
+/// Returned by a Barrier wait, tells which task of the group has been the leader
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Returns true for a single task of each group, the one that completed it
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+/// Wrapper to wait on a Barrier in Future-style
+pub struct FutureBarrierWait<'a> {
+    barrier: &'a Barrier,
+    waiter: WaitNode,
+    // generation we're waiting in, once registered
+    generation: Option<usize>,
+    done: bool,
+}
+
+impl<'a> Future for FutureBarrierWait<'a> {
+    type Output = BarrierWaitResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // only waiter is structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let barrier = this.barrier;
+        if this.generation.is_none() {
+            let mut state = barrier.state.lock();
+            state.count += 1;
+            if state.count >= barrier.n {
+                // we're the last one, release the group while nobody can join the next one
+                state.count = 0;
+                state.generation = state.generation.wrapping_add(1);
+                barrier.waiters.notify_all();
+                this.done = true;
+                return Poll::Ready(BarrierWaitResult(true));
+            }
+            this.generation = Some(state.generation);
+        }
+        if unsafe { barrier.waiters.poll_notified(&this.waiter, cx) }.is_pending() {
+            // the leader may have released the group before we registered, and notified nobody
+            if barrier.state.lock().generation == this.generation.unwrap() {
+                return Poll::Pending;
+            }
+            unsafe { barrier.waiters.leave(&this.waiter); }
+        }
+        this.done = true;
+        Poll::Ready(BarrierWaitResult(false))
+    }
+}
+
+impl<'a> Drop for FutureBarrierWait<'a> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Some(generation) = self.generation {
+            let mut state = self.barrier.state.lock();
+            if state.generation == generation {
+                // not released yet, leave the group
+                state.count -= 1;
+            }
+            unsafe { self.barrier.waiters.leave(&self.waiter); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tokio::runtime::Runtime as ThreadpoolRuntime;
+    use tokio::future::FutureExt;
+
+    use crate::wakers::tests::counting_waker;
+
+    use super::Barrier;
+
+    #[test]
+    fn last_one_leads() {
+        let barrier = Barrier::new(3);
+        let (waker, count) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut first = Box::pin(barrier.wait());
+        let mut second = Box::pin(barrier.wait());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        let mut third = Box::pin(barrier.wait());
+        match third.as_mut().poll(&mut cx) {
+            Poll::Ready(res) => assert!(res.is_leader()),
+            Poll::Pending => panic!("group not released"),
+        }
+        assert_eq!(count.count(), 2);
+        match (first.as_mut().poll(&mut cx), second.as_mut().poll(&mut cx)) {
+            (Poll::Ready(a), Poll::Ready(b)) => assert!(!a.is_leader() && !b.is_leader()),
+            _ => panic!("group not released"),
+        }
+
+        // reusable
+        let mut again = Box::pin(barrier.wait());
+        assert!(again.as_mut().poll(&mut cx).is_pending());
+    }
+
+    #[test]
+    fn cancelled_waiter_leaves_group() {
+        let barrier = Barrier::new(2);
+        let (waker, _) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut first = Box::pin(barrier.wait());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        drop(first);
+        let mut second = Box::pin(barrier.wait());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        let mut third = Box::pin(barrier.wait());
+        assert!(third.as_mut().poll(&mut cx).is_ready());
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn registered_after_release() {
+        let barrier = Barrier::new(2);
+        let (waker, _) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // a waiter that joined the group, but hasn't registered yet
+        let mut first = Box::pin(barrier.wait());
+        barrier.state.lock().count += 1;
+        unsafe { first.as_mut().get_unchecked_mut().generation = Some(0); }
+        let mut second = Box::pin(barrier.wait());
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+        match first.as_mut().poll(&mut cx) {
+            Poll::Ready(res) => assert!(!res.is_leader()),
+            Poll::Pending => panic!("release missed"),
+        }
+    }
+
+    #[test]
+    fn multithread_rendezvous() {
+        env_logger::try_init().ok();
+
+        let barrier = Arc::new(Barrier::new(10));
+        let leaders = Arc::new(AtomicUsize::new(0));
+        let arrived = Arc::new(AtomicUsize::new(0));
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        let (inner_leaders, inner_arrived) = (Arc::clone(&leaders), Arc::clone(&arrived));
+        runtime.block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+            for _ in 0..10 {
+                let barrier = Arc::clone(&barrier);
+                let leaders = Arc::clone(&inner_leaders);
+                let arrived = Arc::clone(&inner_arrived);
+                let mut tx = tx.clone();
+                tokio::spawn(async move {
+                    for round in 1..=5 {
+                        arrived.fetch_add(1, Ordering::SeqCst);
+                        if barrier.wait().await.is_leader() {
+                            leaders.fetch_add(1, Ordering::SeqCst);
+                        }
+                        // nobody gets past the barrier before everybody reached it
+                        assert!(arrived.load(Ordering::SeqCst) >= round * 10);
+                    }
+                    tx.send(()).await.ok();
+                });
+            }
+            for _ in 0..10 {
+                rx.recv().timeout(Duration::from_secs(5)).await.expect("task never completed");
+            }
+        });
+        assert_eq!(leaders.load(Ordering::SeqCst), 5);
+    }
+}
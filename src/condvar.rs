@@ -0,0 +1,437 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::future::Future;
+use std::task::{Poll, Context};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use lock_api::{Mutex as Mutex_, RawMutex, MutexGuard};
+
+use crate::mutex::{Fairness, FutureLock, FutureLockable, FutureRawMutex, Unfair};
+use crate::timeout::Timer;
+use crate::wakers::{WaiterQueue, WaitKind, WaitNode};
+
+/// A Condition Variable to be used with this crate's Mutex and FairMutex
+///
+/// Waiting releases the Mutex and re-acquires it once notified, without blocking the thread.
+/// Like any condition variable it can wake up spuriously, `wait_while` takes care of that.
+pub struct Condvar {
+    waiters: WaiterQueue,
+}
+
+impl Condvar {
+    /// Creates a new Condvar
+    pub const fn new() -> Condvar {
+        Condvar {
+            waiters: WaiterQueue::new(),
+        }
+    }
+
+    /// Wakes up the oldest waiting future, returns false if there was none
+    pub fn notify_one(&self) -> bool {
+        self.waiters.notify_one()
+    }
+
+    /// Wakes up all the waiting futures, returns how many they were
+    pub fn notify_all(&self) -> usize {
+        self.waiters.notify_all()
+    }
+
+    /// Releases the Mutex and waits for a notification, re-acquiring the Mutex before resolving
+    pub fn wait<'a, R, T, F>(&'a self, guard: MutexGuard<'a, FutureRawMutex<R, F>, T>) -> FutureWait<'a, R, T, F>
+    where
+        R: RawMutex + 'a,
+        T: 'a,
+        F: Fairness + 'a,
+    {
+        FutureWait::new(self, guard)
+    }
+
+    /// Waits for notifications as long as the predicate returns true, it isn't called with the Mutex released
+    pub fn wait_while<'a, R, T, F, P>(&'a self, guard: MutexGuard<'a, FutureRawMutex<R, F>, T>, predicate: P) -> FutureWaitWhile<'a, R, T, F, P>
+    where
+        R: RawMutex + 'a,
+        T: 'a,
+        F: Fairness + 'a,
+        P: FnMut(&mut T) -> bool,
+    {
+        FutureWaitWhile {
+            condvar: self,
+            guard: Some(guard),
+            wait: None,
+            predicate,
+        }
+    }
+
+    /// Like `wait`, but stops waiting for a notification after the given timeout
+    pub fn wait_for<'a, R, T, F, Tm>(&'a self, guard: MutexGuard<'a, FutureRawMutex<R, F>, T>, timeout: Duration, timer: &Tm) -> FutureWaitTimeout<'a, R, T, F, Tm::Delay>
+    where
+        R: RawMutex + 'a,
+        T: 'a,
+        F: Fairness + 'a,
+        Tm: Timer,
+    {
+        FutureWaitTimeout {
+            wait: FutureWait::new(self, guard),
+            // a deadline that can't be represented is never reached
+            delay: Instant::now().checked_add(timeout).map(|deadline| timer.delay_until(deadline)),
+            timed_out: false,
+        }
+    }
+
+    /// Like `wait`, but stops waiting for a notification at the given deadline
+    pub fn wait_until<'a, R, T, F, Tm>(&'a self, guard: MutexGuard<'a, FutureRawMutex<R, F>, T>, deadline: Instant, timer: &Tm) -> FutureWaitTimeout<'a, R, T, F, Tm::Delay>
+    where
+        R: RawMutex + 'a,
+        T: 'a,
+        F: Fairness + 'a,
+        Tm: Timer,
+    {
+        FutureWaitTimeout {
+            wait: FutureWait::new(self, guard),
+            delay: Some(timer.delay_until(deadline)),
+            timed_out: false,
+        }
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
+
+/// Tells whether a timed wait has been ended by its timeout rather than by a notification
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// Returns true if the wait timed out
+    pub fn timed_out(self) -> bool {
+        self.0
+    }
+}
+
+/// Wrapper to wait on a Condvar in Future-style
+pub struct FutureWait<'a, R, T, F = Unfair>
+where
+    R: RawMutex + 'a,
+    T: 'a,
+    F: Fairness + 'a,
+{
+    condvar: &'a Condvar,
+    mutex: &'a Mutex_<FutureRawMutex<R, F>, T>,
+    guard: Option<MutexGuard<'a, FutureRawMutex<R, F>, T>>,
+    relock: Option<FutureLock<'a, R, T, F>>,
+    waiter: WaitNode,
+}
+
+impl<'a, R, T, F> FutureWait<'a, R, T, F>
+where
+    R: RawMutex + 'a,
+    T: 'a,
+    F: Fairness + 'a,
+{
+    fn new(condvar: &'a Condvar, guard: MutexGuard<'a, FutureRawMutex<R, F>, T>) -> Self {
+        FutureWait {
+            condvar,
+            mutex: MutexGuard::mutex(&guard),
+            guard: Some(guard),
+            relock: None,
+            waiter: WaitNode::new(WaitKind::Exclusive),
+        }
+    }
+
+    /// Stops waiting for a notification and goes straight to re-acquiring the Mutex
+    fn give_up(&mut self) {
+        // a notification that raced with us is passed on to the next waiter
+        unsafe { self.condvar.waiters.cancel(&self.waiter); }
+        self.guard = None;
+        self.relock = Some(self.mutex.future_lock());
+    }
+}
+
+impl<'a, R, T, F> Future for FutureWait<'a, R, T, F>
+where
+    R: RawMutex + 'a,
+    T: 'a,
+    F: Fairness + 'a,
+{
+    type Output = MutexGuard<'a, FutureRawMutex<R, F>, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // relock and waiter are structurally pinned, relock is only dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.relock.is_none() {
+            let notified = unsafe { this.condvar.waiters.poll_notified(&this.waiter, cx) };
+            // we're registered before releasing the Mutex, so notifications sent while holding it can't get lost
+            this.guard = None;
+            if notified.is_pending() {
+                return Poll::Pending;
+            }
+            this.relock = Some(this.mutex.future_lock());
+        }
+        let relock = this.relock.as_mut().expect("relock future just set");
+        unsafe { Pin::new_unchecked(relock) }.poll(cx)
+    }
+}
+
+impl<'a, R, T, F> Drop for FutureWait<'a, R, T, F>
+where
+    R: RawMutex + 'a,
+    T: 'a,
+    F: Fairness + 'a,
+{
+    fn drop(&mut self) {
+        // a cancelled future must not swallow the notification meant for another waiter
+        unsafe { self.condvar.waiters.cancel(&self.waiter); }
+    }
+}
+
+/// Wrapper to wait on a Condvar until a condition is met in Future-style
+pub struct FutureWaitWhile<'a, R, T, F, P>
+where
+    R: RawMutex + 'a,
+    T: 'a,
+    F: Fairness + 'a,
+    P: FnMut(&mut T) -> bool,
+{
+    condvar: &'a Condvar,
+    guard: Option<MutexGuard<'a, FutureRawMutex<R, F>, T>>,
+    wait: Option<FutureWait<'a, R, T, F>>,
+    predicate: P,
+}
+
+impl<'a, R, T, F, P> Future for FutureWaitWhile<'a, R, T, F, P>
+where
+    R: RawMutex + 'a,
+    T: 'a,
+    F: Fairness + 'a,
+    P: FnMut(&mut T) -> bool,
+{
+    type Output = MutexGuard<'a, FutureRawMutex<R, F>, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // wait is structurally pinned and only dropped in place, predicate isn't pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        loop {
+            if let Some(wait) = this.wait.as_mut() {
+                let guard = match unsafe { Pin::new_unchecked(wait) }.poll(cx) {
+                    Poll::Ready(guard) => guard,
+                    Poll::Pending => return Poll::Pending,
+                };
+                this.wait = None;
+                this.guard = Some(guard);
+            }
+            let mut guard = this.guard.take().expect("FutureWaitWhile polled after completion");
+            if !(this.predicate)(&mut *guard) {
+                return Poll::Ready(guard);
+            }
+            this.wait = Some(this.condvar.wait(guard));
+        }
+    }
+}
+
+/// Wrapper to wait on a Condvar with a timeout in Future-style
+pub struct FutureWaitTimeout<'a, R, T, F, D>
+where
+    R: RawMutex + 'a,
+    T: 'a,
+    F: Fairness + 'a,
+    D: Future<Output = ()>,
+{
+    wait: FutureWait<'a, R, T, F>,
+    delay: Option<D>,
+    timed_out: bool,
+}
+
+impl<'a, R, T, F, D> Future for FutureWaitTimeout<'a, R, T, F, D>
+where
+    R: RawMutex + 'a,
+    T: 'a,
+    F: Fairness + 'a,
+    D: Future<Output = ()>,
+{
+    type Output = (MutexGuard<'a, FutureRawMutex<R, F>, T>, WaitTimeoutResult);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // both futures are structurally pinned, delay is only dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        loop {
+            if let Poll::Ready(guard) = unsafe { Pin::new_unchecked(&mut this.wait) }.poll(cx) {
+                return Poll::Ready((guard, WaitTimeoutResult(this.timed_out)));
+            }
+            if this.wait.relock.is_some() {
+                // notified, the timeout doesn't apply to re-acquiring the Mutex
+                this.delay = None;
+                return Poll::Pending;
+            }
+            let delay = match this.delay.as_mut() {
+                Some(delay) => delay,
+                None => return Poll::Pending,
+            };
+            if unsafe { Pin::new_unchecked(delay) }.poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.delay = None;
+            this.timed_out = true;
+            this.wait.give_up();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::future::Future;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tokio::runtime::current_thread::Runtime as CurrentThreadRuntime;
+
+    use crate::mutex::{FairMutex, FutureLockable, Mutex};
+    use crate::timeout::tests::ManualTimer;
+    use crate::wakers::tests::counting_waker;
+
+    use super::Condvar;
+
+    #[test]
+    fn notify_one_wakes_oldest() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+        assert!(!condvar.notify_one());
+
+        let (waker, count) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut first = Box::pin(condvar.wait(mutex.lock()));
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        // waiting released the mutex
+        let (waker2, count2) = counting_waker();
+        let mut second = Box::pin(condvar.wait(mutex.lock()));
+        assert!(second.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+
+        let guard = mutex.lock();
+        assert!(condvar.notify_one());
+        assert_eq!((count.count(), count2.count()), (1, 0));
+        // the mutex is re-acquired, so the notified waiter can't complete yet
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        drop(guard);
+        assert_eq!(count.count(), 2);
+        let guard = match first.as_mut().poll(&mut cx) {
+            Poll::Ready(guard) => guard,
+            Poll::Pending => panic!("notified waiter didn't get the mutex"),
+        };
+        drop(guard);
+        assert!(second.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+        assert_eq!(count2.count(), 0);
+    }
+
+    #[test]
+    fn notify_all_wakes_everybody() {
+        let mutex = FairMutex::new(0);
+        let condvar = Condvar::new();
+
+        let mut waiters: Vec<_> = (0..50).map(|_| {
+            let (waker, count) = counting_waker();
+            let mut f = Box::pin(condvar.wait(mutex.lock()));
+            assert!(f.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+            (f, waker, count)
+        }).collect();
+
+        assert_eq!(condvar.notify_all(), 50);
+        assert_eq!(condvar.notify_all(), 0);
+        for (f, waker, count) in waiters.iter_mut() {
+            assert_eq!(count.count(), 1);
+            assert!(f.as_mut().poll(&mut Context::from_waker(waker)).is_ready());
+        }
+    }
+
+    #[test]
+    fn cancelled_waiter_passes_notification_on() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+
+        let (waker, _) = counting_waker();
+        let mut first = Box::pin(condvar.wait(mutex.lock()));
+        assert!(first.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        let (waker2, count2) = counting_waker();
+        let mut second = Box::pin(condvar.wait(mutex.lock()));
+        assert!(second.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+
+        assert!(condvar.notify_one());
+        drop(first);
+        assert_eq!(count2.count(), 1);
+        assert!(second.as_mut().poll(&mut Context::from_waker(&waker2)).is_ready());
+    }
+
+    #[test]
+    fn timed_wait() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+        let timer = ManualTimer::default();
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+
+        let mut wait = Box::pin(condvar.wait_for(mutex.lock(), Duration::from_secs(1), &timer));
+        let (waker, _) = counting_waker();
+        assert!(wait.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        timer.fire();
+        let (guard, result) = runtime.block_on(wait);
+        assert!(result.timed_out());
+        // the timed out waiter is gone, nobody gets this notification
+        assert!(!condvar.notify_one());
+        drop(guard);
+
+        let timer = ManualTimer::default();
+        let mut wait = Box::pin(condvar.wait_for(mutex.lock(), Duration::from_secs(1), &timer));
+        assert!(wait.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        assert!(condvar.notify_one());
+        timer.fire();
+        let (guard, result) = runtime.block_on(wait);
+        assert!(!result.timed_out());
+        drop(guard);
+
+        // a deadline too far to be represented never comes
+        let mut wait = Box::pin(condvar.wait_for(mutex.lock(), Duration::MAX, &timer));
+        assert!(wait.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        assert!(wait.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        assert!(condvar.notify_one());
+        let (_guard, result) = runtime.block_on(wait);
+        assert!(!result.timed_out());
+    }
+
+    #[test]
+    fn current_thread_producer_consumer() {
+        env_logger::try_init().ok();
+
+        let queue = Arc::new((Mutex::new(Vec::new()), Condvar::new()));
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        for _ in 0..4 {
+            let queue = Arc::clone(&queue);
+            runtime.spawn(async move {
+                let (mutex, condvar) = &*queue;
+                let mut received = 0;
+                while received < 25 {
+                    let mut items = condvar.wait_while(mutex.future_lock().await, |items| items.is_empty()).await;
+                    items.pop();
+                    received += 1;
+                }
+            });
+        }
+        let producer = Arc::clone(&queue);
+        runtime.spawn(async move {
+            let (mutex, condvar) = &*producer;
+            for i in 0..100 {
+                mutex.future_lock().await.push(i);
+                condvar.notify_one();
+                tokio::timer::delay_for(Duration::from_micros(10)).await;
+            }
+        });
+        runtime.run().unwrap();
+        assert!(queue.0.lock().is_empty());
+    }
+}
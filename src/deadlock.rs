@@ -0,0 +1,316 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::backtrace::Backtrace;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use parking_lot::{const_mutex, Mutex};
+
+use crate::task::current;
+
+pub use crate::task::{track, Tracked};
+
+struct Holding {
+    task: Option<usize>,
+    backtrace: Arc<Backtrace>,
+}
+
+struct Waiting {
+    task: usize,
+    lock: usize,
+}
+
+struct Registry {
+    // lock address to holders
+    holdings: BTreeMap<usize, Vec<Holding>>,
+    // waiting node address to its wait
+    waits: BTreeMap<usize, Waiting>,
+    // lock address to name, for locks currently held or waited for
+    names: BTreeMap<usize, &'static str>,
+}
+
+static REGISTRY: Mutex<Registry> = const_mutex(Registry {
+    holdings: BTreeMap::new(),
+    waits: BTreeMap::new(),
+    names: BTreeMap::new(),
+});
+
+impl Registry {
+    fn forget_name(&mut self, lock: usize) {
+        if !self.holdings.contains_key(&lock) && !self.waits.values().any(|w| w.lock == lock) {
+            self.names.remove(&lock);
+        }
+    }
+}
+
+/// Called by the raw locks on each acquisition
+pub(crate) fn locked(lock: usize, name: Option<&'static str>) {
+    let backtrace = Arc::new(Backtrace::capture());
+    let task = current();
+    let mut registry = REGISTRY.lock();
+    registry.holdings.entry(lock).or_default().push(Holding { task, backtrace });
+    if let Some(name) = name {
+        registry.names.insert(lock, name);
+    }
+}
+
+/// Called by the raw locks on each release, `exclusive` if the lock had a single holder
+pub(crate) fn released(lock: usize, exclusive: bool) {
+    let task = current();
+    let mut registry = REGISTRY.lock();
+    if let Some(holders) = registry.holdings.get_mut(&lock) {
+        if exclusive {
+            holders.clear();
+        }
+        else {
+            // a guard could have been moved to another task, then we can't tell which one is leaving
+            let index = holders.iter().position(|h| h.task == task).unwrap_or(0);
+            if index < holders.len() {
+                holders.swap_remove(index);
+            }
+        }
+        if holders.is_empty() {
+            registry.holdings.remove(&lock);
+            registry.forget_name(lock);
+        }
+    }
+}
+
+/// Called by the WaiterQueue when a node starts waiting
+pub(crate) fn wait_started(node: usize, lock: usize, name: Option<&'static str>) {
+    if let Some(task) = current() {
+        let mut registry = REGISTRY.lock();
+        registry.waits.insert(node, Waiting { task, lock });
+        if let Some(name) = name {
+            registry.names.insert(lock, name);
+        }
+    }
+}
+
+/// Called by the WaiterQueue when a node stops waiting
+pub(crate) fn wait_ended(node: usize) {
+    let mut registry = REGISTRY.lock();
+    if let Some(wait) = registry.waits.remove(&node) {
+        registry.forget_name(wait.lock);
+    }
+}
+
+/// A lock held by a deadlocked task
+#[derive(Clone, Debug)]
+pub struct HeldLock {
+    name: Option<&'static str>,
+    backtrace: Arc<Backtrace>,
+}
+
+impl HeldLock {
+    /// Returns the name of the lock, if it has one
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Returns where the lock has been acquired, captured as `Backtrace::capture` does
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+}
+
+/// A task taking part in a deadlock
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    task_id: usize,
+    waiting_for: Option<&'static str>,
+    held: Vec<HeldLock>,
+}
+
+impl TaskInfo {
+    /// Returns the id of the task, see `task::Tracked::task_id`
+    pub fn task_id(&self) -> usize {
+        self.task_id
+    }
+
+    /// Returns the name of the lock the task is waiting for, if it has one
+    pub fn waiting_for(&self) -> Option<&'static str> {
+        self.waiting_for
+    }
+
+    /// Returns the locks held by the task
+    pub fn held(&self) -> &[HeldLock] {
+        &self.held
+    }
+}
+
+/// Looks for tracked tasks waiting for each other, returns a list of tasks for each cycle found
+///
+/// Waits are considered all needed, so a task selecting between locks can be reported even if it could go on.
+pub fn check_deadlock() -> Vec<Vec<TaskInfo>> {
+    let guard = REGISTRY.lock();
+    let registry = &*guard;
+    // waiting task to (holder task, lock) edges
+    let mut edges: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+    for wait in registry.waits.values() {
+        let holders = registry.holdings.get(&wait.lock).into_iter().flatten();
+        for holder in holders.filter_map(|h| h.task) {
+            edges.entry(wait.task).or_default().push((holder, wait.lock));
+        }
+    }
+
+    let mut cycles = Vec::new();
+    let mut seen = BTreeSet::new();
+    let mut done = BTreeSet::new();
+    for &start in edges.keys() {
+        let mut path = Vec::new();
+        find_cycles(start, &edges, &mut path, &mut done, &mut |cycle| {
+            let tasks: BTreeSet<usize> = cycle.iter().map(|(task, _)| *task).collect();
+            if seen.insert(tasks) {
+                cycles.push(cycle.to_vec());
+            }
+        });
+    }
+
+    cycles.into_iter()
+        .map(|cycle| cycle.into_iter()
+            .map(|(task, lock)| TaskInfo {
+                task_id: task,
+                waiting_for: registry.names.get(&lock).copied(),
+                held: registry.holdings.iter()
+                    .flat_map(|(lock, holders)| holders.iter()
+                        .filter(|h| h.task == Some(task))
+                        .map(move |h| HeldLock {
+                            name: registry.names.get(lock).copied(),
+                            backtrace: Arc::clone(&h.backtrace),
+                        }))
+                    .collect(),
+            })
+            .collect())
+        .collect()
+}
+
+// depth first search, path holds the tasks being visited with the lock they wait for
+fn find_cycles<C>(task: usize, edges: &BTreeMap<usize, Vec<(usize, usize)>>, path: &mut Vec<(usize, usize)>, done: &mut BTreeSet<usize>, found: &mut C)
+where
+    C: FnMut(&[(usize, usize)]),
+{
+    if done.contains(&task) {
+        return;
+    }
+    if let Some(start) = path.iter().position(|(t, _)| *t == task) {
+        found(&path[start..]);
+        return;
+    }
+    for &(holder, lock) in edges.get(&task).into_iter().flatten() {
+        path.push((task, lock));
+        find_cycles(holder, edges, path, done, found);
+        path.pop();
+    }
+    done.insert(task);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::task::Context;
+
+    use crate::latch::Latch;
+    use crate::mutex::{named_mutex, FutureLockable};
+    use crate::rwlock::{named_rwlock, FutureReadable, FutureWriteable};
+    use crate::wakers::tests::counting_waker;
+
+    use super::{check_deadlock, track, TaskInfo};
+
+    // deadlocks involving the given tasks, other tests could be running
+    fn deadlocks_of(tasks: &[usize]) -> Vec<Vec<TaskInfo>> {
+        check_deadlock().into_iter()
+            .filter(|cycle| cycle.iter().any(|t| tasks.contains(&t.task_id())))
+            .collect()
+    }
+
+    #[test]
+    #[allow(clippy::await_holding_lock)]
+    fn crossed_mutexes() {
+        let a = named_mutex("a", ());
+        let b = named_mutex("b", ());
+        let latch = Latch::new(2);
+        let (waker, _) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let first = track(async {
+            let _a = a.future_lock().await;
+            latch.count_down();
+            latch.wait().await;
+            let _b = b.future_lock().await;
+        });
+        let second = track(async {
+            let _b = b.future_lock().await;
+            latch.count_down();
+            latch.wait().await;
+            let _a = a.future_lock().await;
+        });
+        let ids = [first.task_id(), second.task_id()];
+        let (mut first, mut second) = (Box::pin(first), Box::pin(second));
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        assert!(deadlocks_of(&ids).is_empty());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+
+        let cycles = deadlocks_of(&ids);
+        assert_eq!(cycles.len(), 1);
+        let mut waits: Vec<_> = cycles[0].iter().map(|t| (t.waiting_for(), t.held()[0].name())).collect();
+        waits.sort();
+        assert_eq!(waits, [(Some("a"), Some("b")), (Some("b"), Some("a"))]);
+
+        // the guards dropped with the task are released on its behalf
+        drop(first);
+        assert!(deadlocks_of(&ids).is_empty());
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    #[allow(clippy::await_holding_lock)]
+    fn shared_holders() {
+        let data = named_rwlock("data", 0);
+        let index = named_mutex("index", 0);
+        let latch = Latch::new(2);
+        let (waker, _) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let idle = track(async {
+            let _data = data.future_read().await;
+            std::future::pending::<()>().await;
+        });
+        let reader = track(async {
+            let _data = data.future_read().await;
+            latch.count_down();
+            latch.wait().await;
+            let _index = index.future_lock().await;
+        });
+        let writer = track(async {
+            let _index = index.future_lock().await;
+            latch.count_down();
+            latch.wait().await;
+            let _data = data.future_write().await;
+        });
+        let ids = [idle.task_id(), reader.task_id(), writer.task_id()];
+        let (mut idle, mut reader, mut writer) = (Box::pin(idle), Box::pin(reader), Box::pin(writer));
+        assert!(idle.as_mut().poll(&mut cx).is_pending());
+        assert!(reader.as_mut().poll(&mut cx).is_pending());
+        assert!(writer.as_mut().poll(&mut cx).is_pending());
+        // the writer waits for a task that isn't waiting, no deadlock yet
+        assert!(deadlocks_of(&ids).is_empty());
+        assert!(reader.as_mut().poll(&mut cx).is_pending());
+
+        let cycles = deadlocks_of(&ids);
+        assert_eq!(cycles.len(), 1);
+        let mut tasks: Vec<_> = cycles[0].iter().map(TaskInfo::task_id).collect();
+        tasks.sort();
+        assert_eq!(tasks, &ids[1..]);
+
+        drop(writer);
+        assert!(reader.as_mut().poll(&mut cx).is_ready());
+        drop(idle);
+    }
+}
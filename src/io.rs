@@ -0,0 +1,513 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::mutex::{FutureLockableOwned, Mutex, RawMutex_};
+use crate::mutex::owned::{FutureLockOwned, OwnedMutexGuard};
+
+/// Progress of a handle towards holding the shared Mutex
+enum Frame<T> {
+    Idle,
+    Locking(Pin<Box<FutureLockOwned<RawMutex_, T>>>),
+    // the data of the Mutex, whose guard has been leaked: guards aren't Send without the send_guard feature
+    Locked(*mut T),
+}
+
+/// A handle to a shared Mutex, holding it from the first operation of a frame to its end
+struct Handle<T> {
+    lock: Arc<Mutex<T>>,
+    frame: Frame<T>,
+}
+
+// the frame only points into the Mutex kept alive by the handle, and parking_lot doesn't care which thread unlocks it
+// (like with send_guard, parking_lot's deadlock_detection feature isn't supported)
+unsafe impl<T: Send> Send for Handle<T> {}
+// nothing is reachable through a shared reference but the Mutex
+unsafe impl<T: Send> Sync for Handle<T> {}
+
+impl<T> Handle<T> {
+    fn new(inner: T) -> Self {
+        Handle {
+            lock: Arc::new(Mutex::new(inner)),
+            frame: Frame::Idle,
+        }
+    }
+
+    fn share(&self) -> Self {
+        Handle {
+            lock: Arc::clone(&self.lock),
+            frame: Frame::Idle,
+        }
+    }
+
+    /// Runs an operation of the current frame, locking the Mutex first if the frame is starting
+    ///
+    /// A failed operation ends the frame, the other handles shouldn't wait for a broken one.
+    fn poll_op<R, F>(&mut self, cx: &mut Context, op: F) -> Poll<io::Result<R>>
+    where
+        T: Unpin,
+        F: FnOnce(Pin<&mut T>, &mut Context) -> Poll<io::Result<R>>,
+    {
+        loop {
+            match self.frame {
+                Frame::Idle => self.frame = Frame::Locking(Box::pin(self.lock.future_lock_owned())),
+                Frame::Locking(ref mut future) => match future.as_mut().poll(cx) {
+                    Poll::Ready(guard) => self.frame = Frame::Locked(OwnedMutexGuard::leak(guard)),
+                    Poll::Pending => return Poll::Pending,
+                },
+                Frame::Locked(data) => {
+                    let res = op(Pin::new(unsafe { &mut *data }), cx);
+                    if let Poll::Ready(Err(_)) = res {
+                        self.end();
+                    }
+                    return res;
+                },
+            }
+        }
+    }
+
+    /// Runs the last operation of the current frame, releasing the Mutex once it's done
+    fn poll_end<F>(&mut self, cx: &mut Context, op: F) -> Poll<io::Result<()>>
+    where
+        T: Unpin,
+        F: FnOnce(Pin<&mut T>, &mut Context) -> Poll<io::Result<()>>,
+    {
+        let res = self.poll_op(cx, op);
+        if res.is_ready() {
+            self.end();
+        }
+        res
+    }
+
+    /// Ends the current frame, unlocking the Mutex if it's held
+    fn end(&mut self) {
+        if let Frame::Locked(_) = self.frame {
+            unsafe { self.lock.force_unlock(); }
+        }
+        self.frame = Frame::Idle;
+    }
+}
+
+impl<T> Drop for Handle<T> {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+/// A writer shared by many tasks, each clone writes whole frames without interleaving with the others
+///
+/// A clone takes the lock at its first write and keeps it until it flushes or shuts the writer down,
+/// so a frame written with `write_all` followed by `flush` is emitted atomically.
+pub struct SharedWriter<W> {
+    handle: Handle<W>,
+}
+
+impl<W> SharedWriter<W> {
+    /// Wraps a writer to be shared
+    pub fn new(writer: W) -> Self {
+        SharedWriter {
+            handle: Handle::new(writer),
+        }
+    }
+
+    /// Ends the current frame without flushing, letting the other clones write
+    pub fn release(&mut self) {
+        self.handle.end();
+    }
+}
+
+impl<W> Clone for SharedWriter<W> {
+    fn clone(&self) -> Self {
+        SharedWriter {
+            handle: self.handle.share(),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<W: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for SharedWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().handle.poll_op(cx, |w, cx| tokio::io::AsyncWrite::poll_write(w, cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().handle.poll_end(cx, tokio::io::AsyncWrite::poll_flush)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().handle.poll_end(cx, tokio::io::AsyncWrite::poll_shutdown)
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<W: futures_io::AsyncWrite + Unpin> futures_io::AsyncWrite for SharedWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().handle.poll_op(cx, |w, cx| futures_io::AsyncWrite::poll_write(w, cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().handle.poll_end(cx, futures_io::AsyncWrite::poll_flush)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().handle.poll_end(cx, futures_io::AsyncWrite::poll_close)
+    }
+}
+
+/// A reader shared by many tasks, each clone reads whole frames without interleaving with the others
+///
+/// A clone takes the lock at its first read and keeps it until it calls `release` or reaches the end of the stream,
+/// so a frame read with `read_exact` calls followed by `release` is consumed by a single clone.
+pub struct SharedReader<R> {
+    handle: Handle<R>,
+}
+
+impl<R> SharedReader<R> {
+    /// Wraps a reader to be shared
+    pub fn new(reader: R) -> Self {
+        SharedReader {
+            handle: Handle::new(reader),
+        }
+    }
+
+    /// Ends the current frame, letting the other clones read
+    pub fn release(&mut self) {
+        self.handle.end();
+    }
+
+    fn poll_read_with<F>(&mut self, cx: &mut Context, empty: bool, read: F) -> Poll<io::Result<usize>>
+    where
+        R: Unpin,
+        F: FnOnce(Pin<&mut R>, &mut Context) -> Poll<io::Result<usize>>,
+    {
+        let res = self.handle.poll_op(cx, read);
+        if let Poll::Ready(Ok(0)) = res {
+            // the end of the stream, unless the buffer was empty
+            if !empty {
+                self.release();
+            }
+        }
+        res
+    }
+}
+
+impl<R> Clone for SharedReader<R> {
+    fn clone(&self) -> Self {
+        SharedReader {
+            handle: self.handle.share(),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for SharedReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let empty = buf.is_empty();
+        self.get_mut().poll_read_with(cx, empty, |r, cx| tokio::io::AsyncRead::poll_read(r, cx, buf))
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<R: futures_io::AsyncRead + Unpin> futures_io::AsyncRead for SharedReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let empty = buf.is_empty();
+        self.get_mut().poll_read_with(cx, empty, |r, cx| futures_io::AsyncRead::poll_read(r, cx, buf))
+    }
+}
+
+#[cfg(all(test, any(feature = "tokio", feature = "futures-io")))]
+mod tests {
+    #[cfg(feature = "tokio")]
+    use std::cell::RefCell;
+    #[cfg(feature = "futures-io")]
+    use std::future::poll_fn;
+    use std::io;
+    use std::pin::Pin;
+    #[cfg(feature = "tokio")]
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex as StdMutex};
+    use std::task::{Context, Poll};
+    #[cfg(feature = "tokio")]
+    use std::time::Duration;
+
+    #[cfg(feature = "tokio")]
+    use tokio::future::FutureExt;
+    #[cfg(feature = "tokio")]
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    #[cfg(feature = "tokio")]
+    use tokio::runtime::Runtime as ThreadpoolRuntime;
+    use tokio::runtime::current_thread::Runtime as CurrentThreadRuntime;
+
+    use super::{SharedReader, SharedWriter};
+
+    /// A stream moving a single byte at a time, yielding before each one so other tasks can run
+    #[derive(Default)]
+    struct Trickle {
+        data: Arc<StdMutex<Vec<u8>>>,
+        yielded: bool,
+    }
+
+    impl Trickle {
+        fn poll_yield(&mut self, cx: &mut Context) -> Poll<()> {
+            self.yielded = !self.yielded;
+            if self.yielded {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            else {
+                Poll::Ready(())
+            }
+        }
+
+        fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.poll_yield(cx).map(|_| {
+                self.data.lock().unwrap().push(buf[0]);
+                Ok(1)
+            })
+        }
+
+        fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.poll_yield(cx).map(|_| {
+                let mut data = self.data.lock().unwrap();
+                if data.is_empty() || buf.is_empty() {
+                    return Ok(0);
+                }
+                buf[0] = data.remove(0);
+                Ok(1)
+            })
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    impl tokio::io::AsyncWrite for Trickle {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.get_mut().poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    impl tokio::io::AsyncRead for Trickle {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.get_mut().poll_read(cx, buf)
+        }
+    }
+
+    #[cfg(feature = "futures-io")]
+    impl futures_io::AsyncWrite for Trickle {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.get_mut().poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[cfg(feature = "futures-io")]
+    impl futures_io::AsyncRead for Trickle {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.get_mut().poll_read(cx, buf)
+        }
+    }
+
+    // futures-io comes without the extension traits, these stand for write_all, flush and read_exact
+    #[cfg(feature = "futures-io")]
+    async fn write_all<W: futures_io::AsyncWrite + Unpin>(writer: &mut W, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let n = poll_fn(|cx| futures_io::AsyncWrite::poll_write(Pin::new(&mut *writer), cx, buf)).await?;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "futures-io")]
+    async fn flush<W: futures_io::AsyncWrite + Unpin>(writer: &mut W) -> io::Result<()> {
+        poll_fn(|cx| futures_io::AsyncWrite::poll_flush(Pin::new(&mut *writer), cx)).await
+    }
+
+    #[cfg(feature = "futures-io")]
+    async fn read_exact<R: futures_io::AsyncRead + Unpin>(reader: &mut R, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let n = poll_fn(|cx| futures_io::AsyncRead::poll_read(Pin::new(&mut *reader), cx, buf)).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            buf = &mut buf[n..];
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn frames_dont_interleave() {
+        let output = Arc::new(StdMutex::new(Vec::new()));
+        let writer = SharedWriter::new(Trickle { data: Arc::clone(&output), yielded: false });
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        for i in 0..10u8 {
+            let mut writer = writer.clone();
+            runtime.spawn(async move {
+                for _ in 0..10 {
+                    writer.write_all(&[i; 8]).await.unwrap();
+                    writer.flush().await.unwrap();
+                }
+            });
+        }
+        runtime.run().unwrap();
+        let output = output.lock().unwrap();
+        assert_eq!(output.len(), 800);
+        assert!(output.chunks(8).all(|frame| frame.iter().all(|b| *b == frame[0])));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn multithread_frames_dont_interleave() {
+        let output = Arc::new(StdMutex::new(Vec::new()));
+        let writer = SharedWriter::new(Trickle { data: Arc::clone(&output), yielded: false });
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        runtime.block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+            for i in 0..10u8 {
+                // the clones move to tasks running on any thread, in the middle of a frame too
+                let mut writer = writer.clone();
+                let mut tx = tx.clone();
+                tokio::spawn(async move {
+                    for _ in 0..10 {
+                        writer.write_all(&[i; 8]).await.unwrap();
+                        writer.flush().await.unwrap();
+                    }
+                    tx.send(()).await.ok();
+                });
+            }
+            for _ in 0..10 {
+                rx.recv().timeout(Duration::from_secs(5)).await.expect("task never completed");
+            }
+        });
+        let output = output.lock().unwrap();
+        assert_eq!(output.len(), 800);
+        assert!(output.chunks(8).all(|frame| frame.iter().all(|b| *b == frame[0])));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn dropped_mid_frame() {
+        let writer = SharedWriter::new(Trickle::default());
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        let mut first = writer.clone();
+        runtime.block_on(first.write_all(&[1; 4])).unwrap();
+        assert!(writer.handle.lock.try_lock().is_none());
+        // the frame is never flushed, the lock must not stay held
+        drop(first);
+        assert!(writer.handle.lock.try_lock().is_some());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn frames_read_by_one_clone() {
+        let input = Arc::new(StdMutex::new((0..10u8).flat_map(|i| vec![i; 4]).collect()));
+        let reader = SharedReader::new(Trickle { data: input, yielded: false });
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        for _ in 0..5 {
+            let mut reader = reader.clone();
+            let frames = Rc::clone(&frames);
+            runtime.spawn(async move {
+                for _ in 0..2 {
+                    let mut frame = [0; 4];
+                    reader.read_exact(&mut frame).await.unwrap();
+                    reader.release();
+                    frames.borrow_mut().push(frame);
+                }
+            });
+        }
+        runtime.run().unwrap();
+        let frames = frames.borrow();
+        assert_eq!(frames.len(), 10);
+        assert!(frames.iter().all(|frame| frame.iter().all(|b| *b == frame[0])));
+    }
+
+    #[cfg(feature = "futures-io")]
+    #[test]
+    fn futures_io_frames_dont_interleave() {
+        let output = Arc::new(StdMutex::new(Vec::new()));
+        let writer = SharedWriter::new(Trickle { data: Arc::clone(&output), yielded: false });
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        for i in 0..10u8 {
+            let mut writer = writer.clone();
+            runtime.spawn(async move {
+                for _ in 0..10 {
+                    write_all(&mut writer, &[i; 8]).await.unwrap();
+                    flush(&mut writer).await.unwrap();
+                }
+            });
+        }
+        runtime.run().unwrap();
+        let output = output.lock().unwrap();
+        assert_eq!(output.len(), 800);
+        assert!(output.chunks(8).all(|frame| frame.iter().all(|b| *b == frame[0])));
+    }
+
+    #[cfg(feature = "futures-io")]
+    #[test]
+    fn futures_io_dropped_mid_frame() {
+        let writer = SharedWriter::new(Trickle::default());
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        let mut first = writer.clone();
+        runtime.block_on(write_all(&mut first, &[1; 4])).unwrap();
+        assert!(writer.handle.lock.try_lock().is_none());
+        drop(first);
+        assert!(writer.handle.lock.try_lock().is_some());
+
+        let reader = SharedReader::new(Trickle { data: Arc::new(StdMutex::new(vec![1; 8])), yielded: false });
+        let mut first = reader.clone();
+        runtime.block_on(read_exact(&mut first, &mut [0; 4])).unwrap();
+        assert!(reader.handle.lock.try_lock().is_none());
+        drop(first);
+        assert!(reader.handle.lock.try_lock().is_some());
+    }
+
+    #[cfg(feature = "futures-io")]
+    #[test]
+    fn futures_io_frames_read_by_one_clone() {
+        let input = Arc::new(StdMutex::new((0..10u8).flat_map(|i| vec![i; 4]).collect()));
+        let reader = SharedReader::new(Trickle { data: input, yielded: false });
+        let frames = Arc::new(StdMutex::new(Vec::new()));
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        for _ in 0..5 {
+            let mut reader = reader.clone();
+            let frames = Arc::clone(&frames);
+            runtime.spawn(async move {
+                for _ in 0..2 {
+                    let mut frame = [0; 4];
+                    read_exact(&mut reader, &mut frame).await.unwrap();
+                    reader.release();
+                    frames.lock().unwrap().push(frame);
+                }
+            });
+        }
+        runtime.run().unwrap();
+        let frames = frames.lock().unwrap();
+        assert_eq!(frames.len(), 10);
+        assert!(frames.iter().all(|frame| frame.iter().all(|b| *b == frame[0])));
+    }
+}
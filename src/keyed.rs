@@ -0,0 +1,359 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::task::{Poll, Context};
+use std::pin::Pin;
+
+use parking_lot::Mutex as EntriesMutex;
+
+use crate::mutex::{FutureLockableOwned, Mutex, RawMutex_};
+use crate::mutex::owned::{FutureLockOwned, OwnedMutexGuard};
+use crate::rwlock::{FutureReadableOwned, FutureWriteableOwned, RawRwLock_, RwLock};
+use crate::rwlock::owned::{FutureReadOwned, FutureWriteOwned, OwnedRwLockReadGuard, OwnedRwLockWriteGuard};
+
+// lock entries by key, each one kept alive by the guards and futures using it
+struct Entries<K, L> {
+    map: EntriesMutex<HashMap<K, Arc<L>>>,
+}
+
+impl<K: Eq + Hash + Clone, L: Default> Entries<K, L> {
+    fn new() -> Self {
+        Entries {
+            map: EntriesMutex::new(HashMap::new()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.map.lock().len()
+    }
+
+    fn acquire<F>(&self, key: K, start: impl FnOnce(&Arc<L>) -> F) -> FutureKeyed<'_, K, L, F> {
+        let inner = start(self.map.lock().entry(key.clone()).or_default());
+        FutureKeyed {
+            entries: self,
+            key: Some(key),
+            inner: Some(inner),
+        }
+    }
+
+    // called once our handle on the entry has been dropped
+    fn release(&self, key: &K) {
+        let mut map = self.map.lock();
+        // handles are only cloned while holding the map, if the map has the last one nobody can get it anymore
+        if map.get(key).is_some_and(|entry| Arc::strong_count(entry) == 1) {
+            map.remove(key);
+        }
+    }
+}
+
+/// Guard of a keyed lock, the entry is removed when the last guard and waiter of its key go away
+pub struct KeyedGuard<'a, K: Eq + Hash + Clone, L: Default, G> {
+    entries: &'a Entries<K, L>,
+    key: K,
+    guard: Option<G>,
+}
+
+impl<'a, K: Eq + Hash + Clone, L: Default, G> KeyedGuard<'a, K, L, G> {
+    /// Returns the key this guard locks
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<'a, K: Eq + Hash + Clone, L: Default, G> Drop for KeyedGuard<'a, K, L, G> {
+    fn drop(&mut self) {
+        self.guard = None;
+        self.entries.release(&self.key);
+    }
+}
+
+/// Wrapper to lock a key in Future-style
+pub struct FutureKeyed<'a, K: Eq + Hash + Clone, L: Default, F> {
+    entries: &'a Entries<K, L>,
+    // taken once the guard is returned
+    key: Option<K>,
+    inner: Option<F>,
+}
+
+impl<'a, K, L, F> Future for FutureKeyed<'a, K, L, F>
+where
+    K: Eq + Hash + Clone,
+    L: Default,
+    F: Future,
+{
+    type Output = KeyedGuard<'a, K, L, F::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // inner is structurally pinned, only dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = this.inner.as_mut().expect("polled after completion");
+        match unsafe { Pin::new_unchecked(inner) }.poll(cx) {
+            Poll::Ready(guard) => {
+                // the guard keeps the entry alive from now on
+                this.inner = None;
+                Poll::Ready(KeyedGuard {
+                    entries: this.entries,
+                    key: this.key.take().expect("polled after completion"),
+                    guard: Some(guard),
+                })
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, K: Eq + Hash + Clone, L: Default, F> Drop for FutureKeyed<'a, K, L, F> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            // cancels the wait, passing on a wakeup we could have received
+            self.inner = None;
+            self.entries.release(&key);
+        }
+    }
+}
+
+type MutexEntry = Mutex<()>;
+type RwLockEntry = RwLock<()>;
+
+/// Guard returned by `KeyedMutex::lock`
+pub type KeyedMutexGuard<'a, K> = KeyedGuard<'a, K, MutexEntry, OwnedMutexGuard<RawMutex_, ()>>;
+/// Future returned by `KeyedMutex::lock`
+pub type FutureKeyedLock<'a, K> = FutureKeyed<'a, K, MutexEntry, FutureLockOwned<RawMutex_, ()>>;
+/// Guard returned by `KeyedRwLock::read`
+pub type KeyedRwLockReadGuard<'a, K> = KeyedGuard<'a, K, RwLockEntry, OwnedRwLockReadGuard<RawRwLock_, ()>>;
+/// Future returned by `KeyedRwLock::read`
+pub type FutureKeyedRead<'a, K> = FutureKeyed<'a, K, RwLockEntry, FutureReadOwned<RawRwLock_, ()>>;
+/// Guard returned by `KeyedRwLock::write`
+pub type KeyedRwLockWriteGuard<'a, K> = KeyedGuard<'a, K, RwLockEntry, OwnedRwLockWriteGuard<RawRwLock_, ()>>;
+/// Future returned by `KeyedRwLock::write`
+pub type FutureKeyedWrite<'a, K> = FutureKeyed<'a, K, RwLockEntry, FutureWriteOwned<RawRwLock_, ()>>;
+
+/// A set of Future-compatible Mutexes indexed by key
+///
+/// The Mutex of a key is created by the first `lock`, and removed once its last guard and waiter are gone.
+pub struct KeyedMutex<K> {
+    entries: Entries<K, MutexEntry>,
+}
+
+impl<K: Eq + Hash + Clone> KeyedMutex<K> {
+    /// Creates a new KeyedMutex without entries
+    pub fn new() -> Self {
+        KeyedMutex {
+            entries: Entries::new(),
+        }
+    }
+
+    /// Locks the key without blocking
+    pub fn lock(&self, key: K) -> FutureKeyedLock<'_, K> {
+        self.entries.acquire(key, |entry| entry.future_lock_owned())
+    }
+
+    /// Returns the number of keys locked or waited for
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no key is locked or waited for
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for KeyedMutex<K> {
+    fn default() -> Self {
+        KeyedMutex::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone> fmt::Debug for KeyedMutex<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyedMutex").field("len", &self.len()).finish()
+    }
+}
+
+/// A set of Future-compatible RwLocks indexed by key
+///
+/// The RwLock of a key is created by the first `read` or `write`, and removed once its last guard and waiter are gone.
+pub struct KeyedRwLock<K> {
+    entries: Entries<K, RwLockEntry>,
+}
+
+impl<K: Eq + Hash + Clone> KeyedRwLock<K> {
+    /// Creates a new KeyedRwLock without entries
+    pub fn new() -> Self {
+        KeyedRwLock {
+            entries: Entries::new(),
+        }
+    }
+
+    /// Read-locks the key without blocking
+    pub fn read(&self, key: K) -> FutureKeyedRead<'_, K> {
+        self.entries.acquire(key, |entry| entry.future_read_owned())
+    }
+
+    /// Write-locks the key without blocking
+    pub fn write(&self, key: K) -> FutureKeyedWrite<'_, K> {
+        self.entries.acquire(key, |entry| entry.future_write_owned())
+    }
+
+    /// Returns the number of keys locked or waited for
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no key is locked or waited for
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for KeyedRwLock<K> {
+    fn default() -> Self {
+        KeyedRwLock::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone> fmt::Debug for KeyedRwLock<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyedRwLock").field("len", &self.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tokio::runtime::Runtime as ThreadpoolRuntime;
+    use tokio::future::FutureExt;
+
+    use crate::wakers::tests::counting_waker;
+
+    use super::{KeyedMutex, KeyedRwLock};
+
+    fn ready<T>(poll: Poll<T>) -> T {
+        match poll {
+            Poll::Ready(res) => res,
+            Poll::Pending => panic!("lock not acquired"),
+        }
+    }
+
+    #[test]
+    fn shared_entry_collected() {
+        let keyed = KeyedMutex::new();
+        let (waker, count) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let first = ready(Box::pin(keyed.lock("a")).as_mut().poll(&mut cx));
+        let other = ready(Box::pin(keyed.lock("b")).as_mut().poll(&mut cx));
+        let mut second = Box::pin(keyed.lock("a"));
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(keyed.len(), 2);
+
+        drop(first);
+        assert_eq!(count.count(), 1);
+        // the waiter keeps the entry alive
+        assert_eq!(keyed.len(), 2);
+        let second = ready(second.as_mut().poll(&mut cx));
+        assert_eq!(*second.key(), "a");
+        drop(other);
+        assert_eq!(keyed.len(), 1);
+        drop(second);
+        assert!(keyed.is_empty());
+    }
+
+    #[test]
+    fn cancelled_waiter() {
+        let keyed = KeyedMutex::new();
+        let (waker, count) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let guard = ready(Box::pin(keyed.lock(1)).as_mut().poll(&mut cx));
+        let mut first = Box::pin(keyed.lock(1));
+        let mut second = Box::pin(keyed.lock(1));
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        drop(guard);
+        assert_eq!(count.count(), 1);
+        // the wakeup is passed on to the next waiter
+        drop(first);
+        assert_eq!(count.count(), 2);
+        drop(ready(second.as_mut().poll(&mut cx)));
+        drop(second);
+        assert!(keyed.is_empty());
+
+        // a waiter that never got the lock leaves no entry behind
+        let guard = ready(Box::pin(keyed.lock(2)).as_mut().poll(&mut cx));
+        let mut waiter = Box::pin(keyed.lock(2));
+        assert!(waiter.as_mut().poll(&mut cx).is_pending());
+        drop(guard);
+        drop(waiter);
+        assert!(keyed.is_empty());
+    }
+
+    #[test]
+    fn readers_share_key() {
+        let keyed = KeyedRwLock::new();
+        let (waker, count) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let first = ready(Box::pin(keyed.read(1)).as_mut().poll(&mut cx));
+        let second = ready(Box::pin(keyed.read(1)).as_mut().poll(&mut cx));
+        let other = ready(Box::pin(keyed.write(2)).as_mut().poll(&mut cx));
+        let mut writer = Box::pin(keyed.write(1));
+        assert!(writer.as_mut().poll(&mut cx).is_pending());
+        drop(first);
+        assert!(writer.as_mut().poll(&mut cx).is_pending());
+        drop(second);
+        assert!(count.count() > 0);
+        drop(ready(writer.as_mut().poll(&mut cx)));
+        drop(writer);
+        drop(other);
+        assert!(keyed.is_empty());
+    }
+
+    #[test]
+    fn multithread_exclusive_per_key() {
+        env_logger::try_init().ok();
+
+        let keyed = Arc::new(KeyedMutex::new());
+        let busy: Arc<Vec<AtomicBool>> = Arc::new((0..3).map(|_| AtomicBool::new(false)).collect());
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        let inner = Arc::clone(&keyed);
+        runtime.block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(12);
+            for i in 0..12 {
+                let keyed = Arc::clone(&inner);
+                let busy = Arc::clone(&busy);
+                let mut tx = tx.clone();
+                tokio::spawn(async move {
+                    let key = i % 3;
+                    for _ in 0..50 {
+                        let guard = keyed.lock(key).await;
+                        assert!(!busy[key].swap(true, Ordering::SeqCst));
+                        std::thread::yield_now();
+                        busy[key].store(false, Ordering::SeqCst);
+                        drop(guard);
+                    }
+                    tx.send(()).await.ok();
+                });
+            }
+            for _ in 0..12 {
+                rx.recv().timeout(Duration::from_secs(5)).await.expect("task never completed");
+            }
+        });
+        assert!(keyed.is_empty());
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Poll, Context};
+use std::pin::Pin;
+
+use crate::wakers::{WaiterQueue, WaitKind, WaitNode};
+
+/// A Future-compatible one-shot count down Latch
+///
+/// Waiting tasks are released, all at once, when the count reaches zero, and it stays open afterwards.
+pub struct Latch {
+    count: AtomicUsize,
+    waiters: WaiterQueue,
+}
+
+/// Alias for those used to the Java name
+pub type CountDownLatch = Latch;
+
+impl Latch {
+    /// Creates a new Latch that opens after `count` calls to `count_down`
+    pub const fn new(count: usize) -> Latch {
+        Latch {
+            count: AtomicUsize::new(count),
+            waiters: WaiterQueue::new(),
+        }
+    }
+
+    /// Returns the number of `count_down` calls still needed to open the Latch
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Decrements the count, releasing the waiting tasks when it reaches zero
+    ///
+    /// Does nothing once the Latch is open.
+    pub fn count_down(&self) {
+        let mut current = self.count.load(Ordering::Relaxed);
+        loop {
+            if current == 0 {
+                return;
+            }
+            match self.count.compare_exchange_weak(current, current - 1, Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(x) => current = x,
+            }
+        }
+        if current == 1 {
+            self.waiters.notify_all();
+        }
+    }
+
+    /// Returns true if the Latch is open
+    pub fn try_wait(&self) -> bool {
+        self.count() == 0
+    }
+
+    /// Waits for the Latch to open, without blocking
+    pub fn wait(&self) -> FutureLatchWait<'_> {
+        FutureLatchWait {
+            latch: self,
+            waiter: WaitNode::new(WaitKind::Shared),
+        }
+    }
+}
+
+impl fmt::Debug for Latch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Latch").field("count", &self.count()).finish()
+    }
+}
+
+/// Wrapper to wait on a Latch in Future-style
+pub struct FutureLatchWait<'a> {
+    latch: &'a Latch,
+    waiter: WaitNode,
+}
+
+impl<'a> Future for FutureLatchWait<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let latch = self.latch;
+        if latch.try_wait() {
+            unsafe { latch.waiters.leave(&self.waiter); }
+            return Poll::Ready(());
+        }
+        match unsafe { latch.waiters.poll_notified(&self.waiter, cx) } {
+            Poll::Ready(()) => Poll::Ready(()),
+            // the Latch could have opened while we were registering
+            Poll::Pending if latch.try_wait() => {
+                unsafe { latch.waiters.leave(&self.waiter); }
+                Poll::Ready(())
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<'a> Drop for FutureLatchWait<'a> {
+    fn drop(&mut self) {
+        unsafe { self.latch.waiters.leave(&self.waiter); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Context;
+    use std::time::Duration;
+
+    use tokio::runtime::Runtime as ThreadpoolRuntime;
+    use tokio::future::FutureExt;
+
+    use crate::wakers::tests::counting_waker;
+
+    use super::Latch;
+
+    #[test]
+    fn opens_at_zero() {
+        let latch = Latch::new(2);
+        let (waker, count) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut waiters: Vec<_> = (0..5).map(|_| Box::pin(latch.wait())).collect();
+        for w in waiters.iter_mut() {
+            assert!(w.as_mut().poll(&mut cx).is_pending());
+        }
+        latch.count_down();
+        assert_eq!(count.count(), 0);
+        assert!(!latch.try_wait());
+        latch.count_down();
+        assert_eq!(count.count(), 5);
+        for w in waiters.iter_mut() {
+            assert!(w.as_mut().poll(&mut cx).is_ready());
+        }
+        // stays open
+        latch.count_down();
+        assert_eq!(latch.count(), 0);
+        assert!(Box::pin(latch.wait()).as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn multithread_workers_done() {
+        env_logger::try_init().ok();
+
+        let latch = Arc::new(Latch::new(10));
+        let done = Arc::new(AtomicUsize::new(0));
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        runtime.block_on(async move {
+            for _ in 0..10 {
+                let latch = Arc::clone(&latch);
+                let done = Arc::clone(&done);
+                tokio::spawn(async move {
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    done.fetch_add(1, Ordering::SeqCst);
+                    latch.count_down();
+                });
+            }
+            latch.wait().timeout(Duration::from_secs(5)).await.expect("latch never opened");
+            assert_eq!(done.load(Ordering::SeqCst), 10);
+        });
+    }
+}
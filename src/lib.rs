@@ -15,6 +15,48 @@
 pub mod mutex;
 /// parking_lot::RwLock Future implementation
 pub mod rwlock;
+/// Condition Variable to be used with the Future-compatible Mutex
+pub mod condvar;
+/// Future-compatible counting Semaphore
+pub mod semaphore;
+/// Future-compatible Barrier
+pub mod barrier;
+/// Future-compatible count down Latch
+pub mod latch;
+/// Cell written once by an async initialiser, and the Lazy value built on it
+pub mod once_cell;
+/// Deadlock-free acquisition of several locks at once
+pub mod lock_all;
+/// Mutex and RwLock locked by key, with lazily created entries
+pub mod keyed;
+/// Concurrent HashMap split over Future-compatible RwLock shards
+pub mod sharded_map;
+/// Timed lock Futures and the Timer they rely on
+pub mod timeout;
+/// AsyncRead and AsyncWrite adapters shared by many tasks, one frame at a time
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+pub mod io;
+
+/// parking_lot raw locks with Send guards
+#[cfg(feature = "send_guard")]
+pub mod send_guard;
+/// Contention statistics of Mutexes and RwLocks
+#[cfg(feature = "stats")]
+pub mod stats;
+/// Detection of tasks deadlocked on Future-compatible locks
+#[cfg(feature = "deadlock_detection")]
+pub mod deadlock;
+/// Validation of the order tasks acquire Future-compatible locks in
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+/// Tasks tracked while holding or waiting for locks
+#[cfg(any(feature = "deadlock_detection", feature = "lockdep"))]
+pub mod task;
+/// Reports of Future-compatible locks held too long
+#[cfg(feature = "watchdog")]
+pub mod watchdog;
+
+mod wakers;
 
 //Re-export `parking_lot` to avoid version mismatch
 pub use parking_lot;
@@ -0,0 +1,330 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::future::Future;
+use std::task::{Poll, Context};
+use std::pin::Pin;
+
+use lock_api::{Mutex, MutexGuard, RawMutex, RawRwLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::mutex::{Fairness, FutureLock, FutureLockable, FutureRawMutex};
+use crate::rwlock::{FutureRawRwLock, FutureReadable, FutureWriteable};
+use crate::rwlock::read::{try_read_behind_upgrade, FutureRead};
+use crate::rwlock::write::FutureWrite;
+
+/// A lock that can be acquired together with others by `lock_all`
+pub trait Lockable {
+    /// Guard returned once locked
+    type Guard;
+    /// Future resolving to the Guard
+    type Future: Future<Output = Self::Guard>;
+
+    /// Attempts to acquire the lock without waiting
+    fn try_acquire(&self) -> Option<Self::Guard>;
+
+    /// Acquires the lock without blocking
+    fn acquire(&self) -> Self::Future;
+
+    /// Identifies the underlying lock, usually by its address, so a lock can't be passed twice to `lock_all`
+    fn id(&self) -> usize;
+}
+
+impl<'a, R, T, F> Lockable for &'a Mutex<FutureRawMutex<R, F>, T>
+where
+    R: RawMutex + 'a,
+    T: 'a,
+    F: Fairness + 'a,
+{
+    type Guard = MutexGuard<'a, FutureRawMutex<R, F>, T>;
+    type Future = FutureLock<'a, R, T, F>;
+
+    fn try_acquire(&self) -> Option<Self::Guard> {
+        self.try_lock()
+    }
+
+    fn acquire(&self) -> Self::Future {
+        self.future_lock()
+    }
+
+    fn id(&self) -> usize {
+        *self as *const Mutex<FutureRawMutex<R, F>, T> as usize
+    }
+}
+
+/// Read-locks an RwLock as part of a `lock_all`
+pub struct Read<'a, R: RawRwLock, T>(pub &'a RwLock<FutureRawRwLock<R>, T>);
+
+impl<'a, R, T> Lockable for Read<'a, R, T>
+where
+    R: RawRwLock + 'a,
+    T: 'a,
+{
+    type Guard = RwLockReadGuard<'a, FutureRawRwLock<R>, T>;
+    type Future = FutureRead<'a, R, T>;
+
+    fn try_acquire(&self) -> Option<Self::Guard> {
+        try_read_behind_upgrade(self.0)
+    }
+
+    fn acquire(&self) -> Self::Future {
+        self.0.future_read()
+    }
+
+    fn id(&self) -> usize {
+        self.0 as *const RwLock<FutureRawRwLock<R>, T> as usize
+    }
+}
+
+/// Write-locks an RwLock as part of a `lock_all`
+pub struct Write<'a, R: RawRwLock, T>(pub &'a RwLock<FutureRawRwLock<R>, T>);
+
+impl<'a, R, T> Lockable for Write<'a, R, T>
+where
+    R: RawRwLock + 'a,
+    T: 'a,
+{
+    type Guard = RwLockWriteGuard<'a, FutureRawRwLock<R>, T>;
+    type Future = FutureWrite<'a, R, T>;
+
+    fn try_acquire(&self) -> Option<Self::Guard> {
+        self.0.try_write()
+    }
+
+    fn acquire(&self) -> Self::Future {
+        self.0.future_write()
+    }
+
+    fn id(&self) -> usize {
+        self.0 as *const RwLock<FutureRawRwLock<R>, T> as usize
+    }
+}
+
+/// A tuple of Lockables, that `lock_all` acquires all together
+pub trait LockSet {
+    /// Tuple of the Guards
+    type Guards;
+    #[doc(hidden)]
+    type State;
+
+    #[doc(hidden)]
+    fn new_state() -> Self::State;
+
+    #[doc(hidden)]
+    fn check_distinct(&self);
+
+    #[doc(hidden)]
+    fn poll_lock(&self, state: Pin<&mut Self::State>, cx: &mut Context) -> Poll<Self::Guards>;
+}
+
+macro_rules! lock_set {
+    ($($idx:tt $L:ident),+) => {
+        impl<$($L: Lockable),+> LockSet for ($($L,)+) {
+            type Guards = ($($L::Guard,)+);
+            // the lock being waited for, and its future
+            type State = (Option<usize>, ($(Option<$L::Future>,)+));
+
+            fn new_state() -> Self::State {
+                (None, ($(None::<$L::Future>,)+))
+            }
+
+            fn check_distinct(&self) {
+                // a lock passed twice would be busy forever, held by ourselves
+                let ids = [$(self.$idx.id(),)+];
+                for (i, id) in ids.iter().enumerate() {
+                    if let Some(first) = ids[..i].iter().position(|other| other == id) {
+                        panic!("lock_all: locks {} and {} are the same lock", first, i);
+                    }
+                }
+            }
+
+            fn poll_lock(&self, state: Pin<&mut Self::State>, cx: &mut Context) -> Poll<Self::Guards> {
+                // the futures are structurally pinned, and only dropped in place
+                let (waiting, futures) = unsafe { state.get_unchecked_mut() };
+                loop {
+                    // guards never outlive a poll, we hold nothing while waiting
+                    let mut held = ($(None::<$L::Guard>,)+);
+                    if let Some(w) = *waiting {
+                        $(
+                            if w == $idx {
+                                let future = futures.$idx.as_mut().expect("waited lock has no future");
+                                match unsafe { Pin::new_unchecked(future) }.poll(cx) {
+                                    Poll::Ready(guard) => held.$idx = Some(guard),
+                                    Poll::Pending => return Poll::Pending,
+                                }
+                                futures.$idx = None;
+                            }
+                        )+
+                        *waiting = None;
+                    }
+                    let mut blocked = None;
+                    $(
+                        if blocked.is_none() && held.$idx.is_none() {
+                            match self.$idx.try_acquire() {
+                                Some(guard) => held.$idx = Some(guard),
+                                None => blocked = Some($idx),
+                            }
+                        }
+                    )+
+                    match blocked {
+                        None => return Poll::Ready(($(held.$idx.take().expect("lock not held"),)+)),
+                        Some(b) => {
+                            // back off: release everything we got, then wait for the busy one alone
+                            drop(held);
+                            $(
+                                if b == $idx {
+                                    futures.$idx = Some(self.$idx.acquire());
+                                }
+                            )+
+                            *waiting = Some(b);
+                        },
+                    }
+                }
+            }
+        }
+    };
+}
+
+lock_set!(0 A);
+lock_set!(0 A, 1 B);
+lock_set!(0 A, 1 B, 2 C);
+lock_set!(0 A, 1 B, 2 C, 3 D);
+lock_set!(0 A, 1 B, 2 C, 3 D, 4 E);
+lock_set!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F);
+
+/// Wrapper to acquire a set of locks in Future-style
+pub struct FutureLockAll<S: LockSet> {
+    locks: S,
+    state: S::State,
+}
+
+impl<S: LockSet> Future for FutureLockAll<S> {
+    type Output = S::Guards;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // state is structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        this.locks.poll_lock(unsafe { Pin::new_unchecked(&mut this.state) }, cx)
+    }
+}
+
+/// Acquires a tuple of locks without blocking and without deadlocking, whatever the order other tasks use
+///
+/// Mutexes are passed by reference, RwLocks wrapped in `Read` or `Write`.
+/// If a lock is busy every other lock is released while waiting for it, then the others are tried again.
+/// Passing the same lock twice panics, even if it's only read both times.
+pub fn lock_all<S: LockSet>(locks: S) -> FutureLockAll<S> {
+    locks.check_distinct();
+    FutureLockAll {
+        locks,
+        state: S::new_state(),
+    }
+}
+
+/// Acquires a list of locks without blocking and without deadlocking, see `lock_all::lock_all`
+#[macro_export]
+macro_rules! lock_all {
+    ($($lock:expr),+ $(,)?) => {
+        $crate::lock_all::lock_all(($($lock,)+))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tokio::runtime::Runtime as ThreadpoolRuntime;
+    use tokio::runtime::current_thread::Runtime as CurrentThreadRuntime;
+    use tokio::future::FutureExt;
+
+    use crate::mutex::Mutex;
+    use crate::rwlock::RwLock;
+    use crate::wakers::tests::counting_waker;
+
+    use super::{lock_all, Read, Write};
+
+    #[test]
+    fn holds_nothing_while_waiting() {
+        let a = Mutex::new(1);
+        let b = Mutex::new(2);
+        let guard = b.lock();
+
+        let (waker, count) = counting_waker();
+        let mut both = Box::pin(lock_all((&a, &b)));
+        assert!(both.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        // a has been released while waiting for b
+        assert!(a.try_lock().is_some());
+        drop(guard);
+        assert_eq!(count.count(), 1);
+        let (ga, gb) = match both.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(guards) => guards,
+            Poll::Pending => panic!("locks not acquired"),
+        };
+        assert_eq!((*ga, *gb), (1, 2));
+    }
+
+    #[test]
+    fn mixed_locks_macro() {
+        let a = Mutex::new(1);
+        let b = RwLock::new(2);
+        let c = RwLock::new(3);
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        runtime.block_on(async {
+            let (mut a, mut b, c) = crate::lock_all!(&a, Write(&b), Read(&c)).await;
+            *a += *c;
+            *b += *c;
+        });
+        assert_eq!((*a.lock(), *b.read()), (4, 5));
+    }
+
+    #[test]
+    #[should_panic(expected = "lock_all: locks 0 and 2 are the same lock")]
+    fn same_lock_twice() {
+        let a = Mutex::new(1);
+        let b = RwLock::new(2);
+        drop(lock_all((Read(&b), &a, Write(&b))));
+    }
+
+    #[test]
+    fn multithread_opposite_orders() {
+        env_logger::try_init().ok();
+
+        let accounts = Arc::new((Mutex::new(1000i64), Mutex::new(1000i64)));
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        let inner = Arc::clone(&accounts);
+        runtime.block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(20);
+            for i in 0..20 {
+                let accounts = Arc::clone(&inner);
+                let mut tx = tx.clone();
+                tokio::spawn(async move {
+                    for _ in 0..100 {
+                        // half the tasks lock in the opposite order
+                        let (a, b) = &*accounts;
+                        if i % 2 == 0 {
+                            let (mut from, mut to) = lock_all((a, b)).await;
+                            *from -= 1;
+                            *to += 1;
+                        }
+                        else {
+                            let (mut from, mut to) = lock_all((b, a)).await;
+                            *from -= 1;
+                            *to += 1;
+                        }
+                    }
+                    tx.send(()).await.ok();
+                });
+            }
+            for _ in 0..20 {
+                rx.recv().timeout(Duration::from_secs(5)).await.expect("deadlocked");
+            }
+        });
+        assert_eq!((*accounts.0.lock(), *accounts.1.lock()), (1000, 1000));
+    }
+}
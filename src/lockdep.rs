@@ -0,0 +1,411 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::panic::Location;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use parking_lot::{const_mutex, Mutex};
+
+use crate::task::current;
+
+/// A class of locks following the same ordering rules, usually declared as a static
+///
+/// Locks of a class must always be acquired in the same order relative to locks of the other classes,
+/// and, if both classes have a level, after the locks of lower levels.
+/// Locks of the same class can be nested in any order.
+#[derive(Debug)]
+pub struct LockClass {
+    name: &'static str,
+    level: Option<u32>,
+}
+
+impl LockClass {
+    /// Creates a new LockClass, ordered by observation only
+    pub const fn new(name: &'static str) -> LockClass {
+        LockClass {
+            name,
+            level: None,
+        }
+    }
+
+    /// Creates a new LockClass, whose locks must be acquired after the ones of lower levels
+    pub const fn with_level(name: &'static str, level: u32) -> LockClass {
+        LockClass {
+            name,
+            level: Some(level),
+        }
+    }
+
+    /// Returns the name of the class
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the level of the class, if it has one
+    pub const fn level(&self) -> Option<u32> {
+        self.level
+    }
+
+    fn id(&'static self) -> usize {
+        self as *const LockClass as usize
+    }
+}
+
+type Site = &'static Location<'static>;
+
+/// A lock acquired in an order that contradicts the levels or a previously observed order
+#[derive(Debug)]
+pub struct OrderViolation {
+    held: &'static LockClass,
+    held_at: Site,
+    acquired: &'static LockClass,
+    acquired_at: Site,
+    // where a lock of the acquired class was held while acquiring a lock that comes before the held one
+    previous: Option<(&'static LockClass, Site, &'static LockClass, Site)>,
+}
+
+impl OrderViolation {
+    /// Returns the class of the lock already held
+    pub fn held(&self) -> &'static LockClass {
+        self.held
+    }
+
+    /// Returns where the lock already held has been acquired
+    pub fn held_at(&self) -> &'static Location<'static> {
+        self.held_at
+    }
+
+    /// Returns the class of the lock being acquired
+    pub fn acquired(&self) -> &'static LockClass {
+        self.acquired
+    }
+
+    /// Returns where the lock is being acquired
+    pub fn acquired_at(&self) -> &'static Location<'static> {
+        self.acquired_at
+    }
+}
+
+impl fmt::Display for OrderViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "lock order violation: {} acquired at {} while holding {} acquired at {}",
+            self.acquired.name, self.acquired_at, self.held.name, self.held_at)?;
+        match self.previous {
+            Some((first, first_at, then, then_at)) => write!(f, ", but {} has been acquired at {} while holding {} acquired at {}",
+                then.name, then_at, first.name, first_at),
+            None => write!(f, ", but level {} comes after level {}",
+                self.held.level.unwrap_or_default(), self.acquired.level.unwrap_or_default()),
+        }
+    }
+}
+
+fn panic_on_violation(violation: &OrderViolation) {
+    panic!("{}", violation);
+}
+
+static HANDLER: Mutex<fn(&OrderViolation)> = const_mutex(panic_on_violation as fn(&OrderViolation));
+
+/// Sets the function called on every violation, by default it panics
+///
+/// A handler that doesn't panic lets the lock be acquired anyway, e.g. to only log the violations.
+pub fn set_violation_handler(handler: fn(&OrderViolation)) {
+    *HANDLER.lock() = handler;
+}
+
+/// Who holds locks: a tracked task, or else the current thread
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Holder {
+    Task(usize),
+    Thread(usize),
+}
+
+fn holder() -> Holder {
+    static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static THREAD_ID: usize = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+    }
+    match current() {
+        Some(task) => Holder::Task(task),
+        None => Holder::Thread(THREAD_ID.with(|id| *id)),
+    }
+}
+
+struct Held {
+    lock: usize,
+    class: &'static LockClass,
+    // unknown for blocking acquisitions, which are recorded only to be matched with their release
+    site: Option<Site>,
+}
+
+struct Edge {
+    first: &'static LockClass,
+    first_at: Site,
+    then: &'static LockClass,
+    then_at: Site,
+}
+
+struct Registry {
+    held: BTreeMap<Holder, Vec<Held>>,
+    // class id to the class ids observed after it
+    order: BTreeMap<usize, BTreeMap<usize, Edge>>,
+}
+
+static REGISTRY: Mutex<Registry> = const_mutex(Registry {
+    held: BTreeMap::new(),
+    order: BTreeMap::new(),
+});
+
+impl Registry {
+    /// Returns the first edge of a path of observed orders from `from` to `to`, if any
+    fn path(&self, from: usize, to: usize) -> Option<&Edge> {
+        let mut visited = BTreeSet::new();
+        let mut stack: Vec<(usize, Option<&Edge>)> = vec![(from, None)];
+        while let Some((class, first)) = stack.pop() {
+            if !visited.insert(class) {
+                continue;
+            }
+            for (next, edge) in self.order.get(&class).into_iter().flatten() {
+                let first = first.or(Some(edge));
+                if *next == to {
+                    return first;
+                }
+                stack.push((*next, first));
+            }
+        }
+        None
+    }
+}
+
+/// Called when a Future is about to acquire a lock with a class, before trying, validates the order
+pub(crate) fn acquiring(class: &'static LockClass, site: Site) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let holder = holder();
+    let violation = {
+        let mut guard = REGISTRY.lock();
+        let registry = &mut *guard;
+        let mut violation = None;
+        for (held, held_at) in registry.held.get(&holder).into_iter().flatten().filter_map(|h| h.site.map(|site| (h, site))) {
+            if held.class.id() == class.id() {
+                continue;
+            }
+            let previous = match (held.class.level, class.level) {
+                (Some(held_level), Some(level)) if level <= held_level => Some(None),
+                _ => registry.path(class.id(), held.class.id())
+                    .map(|edge| Some((edge.first, edge.first_at, edge.then, edge.then_at))),
+            };
+            if let Some(previous) = previous {
+                violation = Some(OrderViolation {
+                    held: held.class,
+                    held_at,
+                    acquired: class,
+                    acquired_at: site,
+                    previous,
+                });
+                break;
+            }
+        }
+        if violation.is_none() {
+            for (held, held_at) in registry.held.get(&holder).into_iter().flatten().filter_map(|h| h.site.map(|site| (h, site))) {
+                if held.class.id() != class.id() {
+                    registry.order.entry(held.class.id()).or_default().entry(class.id()).or_insert(Edge {
+                        first: held.class,
+                        first_at: held_at,
+                        then: class,
+                        then_at: site,
+                    });
+                }
+            }
+        }
+        violation
+    };
+    // outside of the registry lock, the handler could panic or lock something itself
+    if let Some(violation) = violation {
+        let handler = *HANDLER.lock();
+        handler(&violation);
+    }
+}
+
+/// Called on every acquisition of a lock with a class, records it as held by the current task
+pub(crate) fn acquired(lock: usize, class: &'static LockClass) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let holder = holder();
+    REGISTRY.lock().held.entry(holder).or_default().push(Held { lock, class, site: None });
+}
+
+/// Called when a Future acquires a lock with a class, the hold is then checked against the later acquisitions
+pub(crate) fn located(lock: usize, site: Site) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let holder = holder();
+    let mut registry = REGISTRY.lock();
+    let held = registry.held.get_mut(&holder).and_then(|held| held.iter_mut().rev().find(|h| h.lock == lock && h.site.is_none()));
+    if let Some(held) = held {
+        held.site = Some(site);
+    }
+}
+
+/// Called on every release of a lock with a class
+pub(crate) fn released(lock: usize) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let holder = holder();
+    let mut registry = REGISTRY.lock();
+    // the guard could have been moved, then look for its lock everywhere
+    let found = registry.held.get(&holder)
+        .and_then(|held| held.iter().rposition(|h| h.lock == lock))
+        .map(|index| (holder, index))
+        .or_else(|| registry.held.iter()
+            .find_map(|(holder, held)| held.iter().rposition(|h| h.lock == lock).map(|index| (*holder, index))));
+    if let Some((holder, index)) = found {
+        let held = registry.held.get_mut(&holder).expect("holder just found");
+        held.remove(index);
+        if held.is_empty() {
+            registry.held.remove(&holder);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::task::{Context, Poll};
+
+    use crate::latch::Latch;
+    use crate::mutex::{mutex_with_class, FutureLockable, Mutex};
+    use crate::rwlock::{rwlock_with_class, FutureReadable, FutureWriteable};
+    use crate::task::track;
+    use crate::wakers::tests::counting_waker;
+
+    use super::LockClass;
+
+    fn poll_now<F: Future>(future: F) -> F::Output {
+        let (waker, _) = counting_waker();
+        match Box::pin(future).as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(res) => res,
+            Poll::Pending => panic!("lock busy"),
+        }
+    }
+
+    fn panic_message<F: FnOnce()>(f: F) -> String {
+        let err = catch_unwind(AssertUnwindSafe(f)).expect_err("no violation detected");
+        err.downcast::<String>().map(|s| *s).unwrap_or_default()
+    }
+
+    #[test]
+    // release builds skip the validation
+    #[cfg_attr(not(debug_assertions), ignore)]
+    fn inverted_order() {
+        static FIRST: LockClass = LockClass::new("first");
+        static SECOND: LockClass = LockClass::new("second");
+        static THIRD: LockClass = LockClass::new("third");
+        let first = mutex_with_class(&FIRST, ());
+        let second = rwlock_with_class(&SECOND, ());
+        let third = mutex_with_class(&THIRD, ());
+
+        {
+            let _first = poll_now(first.future_lock());
+            let _second = poll_now(second.future_read());
+        }
+        {
+            let _second = poll_now(second.future_write());
+            let _third = poll_now(third.future_lock());
+            // the same order again is fine
+            drop(_third);
+            let _third = poll_now(third.future_lock());
+        }
+        let message = panic_message(|| {
+            let _third = poll_now(third.future_lock());
+            let _first = poll_now(first.future_lock());
+        });
+        assert!(message.starts_with("lock order violation: first acquired at src/lockdep.rs"), "{}", message);
+        // the order is contradicted through second, first has never been held together with third
+        assert!(message.contains("but second has been acquired at src/lockdep.rs"), "{}", message);
+        // nothing has been acquired by the failed attempt
+        let _first = poll_now(first.future_lock());
+        let _second = poll_now(second.future_read());
+    }
+
+    #[test]
+    // release builds skip the validation
+    #[cfg_attr(not(debug_assertions), ignore)]
+    fn levels() {
+        static LOW: LockClass = LockClass::with_level("low", 1);
+        static HIGH: LockClass = LockClass::with_level("high", 2);
+        let low = mutex_with_class(&LOW, ());
+        let high = mutex_with_class(&HIGH, ());
+        let other = Mutex::new(());
+
+        // never observed, but the levels are enough
+        let message = panic_message(|| {
+            let _high = poll_now(high.future_lock());
+            let _other = poll_now(other.future_lock());
+            let _low = poll_now(low.future_lock());
+        });
+        assert!(message.ends_with("but level 2 comes after level 1"), "{}", message);
+    }
+
+    #[test]
+    // release builds skip the validation
+    #[cfg_attr(not(debug_assertions), ignore)]
+    fn blocking_releases_keep_records() {
+        static OUTER: LockClass = LockClass::new("blocking_outer");
+        static INNER: LockClass = LockClass::new("blocking_inner");
+        let outer = mutex_with_class(&OUTER, ());
+        let inner = rwlock_with_class(&INNER, ());
+        {
+            let _outer = poll_now(outer.future_lock());
+            let _inner = poll_now(inner.future_read());
+        }
+
+        let message = panic_message(|| {
+            let _inner = poll_now(inner.future_read());
+            // a blocking read and its release must leave the record of the future read alone
+            drop(inner.read());
+            let _outer = poll_now(outer.future_lock());
+        });
+        assert!(message.starts_with("lock order violation: blocking_outer acquired at src/lockdep.rs"), "{}", message);
+    }
+
+    #[test]
+    #[allow(clippy::await_holding_lock)]
+    fn tasks_are_separate() {
+        static OUTER: LockClass = LockClass::new("outer");
+        static INNER: LockClass = LockClass::new("inner");
+        let outer = mutex_with_class(&OUTER, ());
+        let inner = mutex_with_class(&INNER, ());
+        let latch = Latch::new(1);
+        let (waker, _) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // interleaved on the same thread, but locking independently
+        let mut first = Box::pin(track(async {
+            let _inner = inner.future_lock().await;
+            latch.wait().await;
+        }));
+        let mut second = Box::pin(track(async {
+            let _outer = outer.future_lock().await;
+            let _inner = inner.future_lock().await;
+        }));
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        let mut third = Box::pin(track(async {
+            let _outer = outer.future_lock().await;
+        }));
+        assert!(third.as_mut().poll(&mut cx).is_ready());
+        latch.count_down();
+        assert!(first.as_mut().poll(&mut cx).is_ready());
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+    }
+}
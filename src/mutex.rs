@@ -7,174 +7,317 @@
 
 use std::marker::PhantomData;
 use std::future::Future;
-use std::task::{Poll, Context, Waker};
+use std::task::{Poll, Context};
 use std::pin::Pin;
-use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
-use std::ptr::null_mut;
-use crossbeam_queue::SegQueue;
+use std::time::{Duration, Instant};
 
-use lock_api::{Mutex as Mutex_, RawMutex, MutexGuard};
+use lock_api::{Mutex as Mutex_, RawMutex, RawMutexFair, MutexGuard};
 
-use parking_lot::RawMutex as RawMutex_;
+#[cfg(not(feature = "send_guard"))]
+pub(crate) use parking_lot::RawMutex as RawMutex_;
+#[cfg(feature = "send_guard")]
+pub(crate) use crate::send_guard::RawMutex as RawMutex_;
+
+use crate::timeout::{FutureTimeout, Timer};
+use crate::wakers::{WaiterQueue, WaitKind, WaitNode};
+#[cfg(feature = "stats")]
+use crate::stats::{HoldStats, LockStats};
+#[cfg(feature = "lockdep")]
+use crate::lockdep::LockClass;
+
+/// FutureLockOwned module
+pub mod owned;
+
+/// Trait to permit FutureLockOwned implementation on Arc'd Mutex
+pub use owned::FutureLockableOwned;
+
+/// ReentrantMutex module
+pub mod reentrant;
+
+/// Mutex that can be locked again by the task holding it
+pub use reentrant::{ReentrantMutex, TaskId};
 
 /// a Future-compatible parking_lot::Mutex
 pub type Mutex<T> = Mutex_<FutureRawMutex<RawMutex_>, T>;
 
-/// RawMutex implementor that collects Wakers to wake them up when unlocked
-pub struct FutureRawMutex<R> where R: RawMutex {
-    locking: AtomicBool,
-    wakers: AtomicPtr<SegQueue<Waker>>,
-    inner: R,
+/// a Future-compatible parking_lot::Mutex that hands the lock over to waiting futures in FIFO order
+pub type FairMutex<T> = Mutex_<FutureRawMutex<RawMutex_, Fair>, T>;
+
+/// Creates a new Mutex in a const context, so it can be declared as a plain static
+pub const fn const_mutex<T>(val: T) -> Mutex<T> {
+    Mutex::const_new(<FutureRawMutex<RawMutex_> as RawMutex>::INIT, val)
 }
 
-impl<R> FutureRawMutex<R> where R: RawMutex {
-    // this is needed to avoid sequences like that:
-    // * thread 1 gains lock
-    // * thread 2 try lock
-    // * thread 1 unlock
-    // * thread 2 register waker
-    // this creates a situation similar to a deadlock, where the future isn't waked up by nobody
-    fn atomic_lock(&self) {
-        while self.locking.compare_and_swap(false, true, Ordering::Relaxed) {}
-    }
+/// Creates a new FairMutex in a const context, so it can be declared as a plain static
+pub const fn const_fair_mutex<T>(val: T) -> FairMutex<T> {
+    FairMutex::const_new(<FutureRawMutex<RawMutex_, Fair> as RawMutex>::INIT, val)
+}
 
-    fn atomic_unlock(&self) {
-        self.locking.store(false, Ordering::Relaxed);
-    }
+/// Creates a new Mutex whose tracing events are tagged with `name`, in a const context too
+pub const fn named_mutex<T>(name: &'static str, val: T) -> Mutex<T> {
+    Mutex::const_new(FutureRawMutex::named(name), val)
+}
 
-    fn register_waker(&self, waker: &Waker) {
-        let v = unsafe { &mut *self.wakers.load(Ordering::Relaxed) };
-        v.push(waker.clone());
-        // implicitly unlock
-        self.atomic_unlock();
-    }
+/// Creates a new FairMutex whose tracing events are tagged with `name`, in a const context too
+pub const fn named_fair_mutex<T>(name: &'static str, val: T) -> FairMutex<T> {
+    FairMutex::const_new(FutureRawMutex::named(name), val)
+}
+
+/// Creates a new Mutex whose future acquisitions follow the ordering rules of `class`, in a const context too
+#[cfg(feature = "lockdep")]
+pub const fn mutex_with_class<T>(class: &'static LockClass, val: T) -> Mutex<T> {
+    Mutex::const_new(FutureRawMutex::with_class(class), val)
+}
+
+/// Creates a new FairMutex whose future acquisitions follow the ordering rules of `class`, in a const context too
+#[cfg(feature = "lockdep")]
+pub const fn fair_mutex_with_class<T>(class: &'static LockClass, val: T) -> FairMutex<T> {
+    FairMutex::const_new(FutureRawMutex::with_class(class), val)
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// Unlocking policy of a FutureRawMutex
+pub trait Fairness: private::Sealed {
+    #[doc(hidden)]
+    const FAIR: bool;
+}
+
+/// Unlocking releases the lock and wakes the oldest waiter, anybody can take the lock before it
+pub struct Unfair;
+
+impl private::Sealed for Unfair {}
 
-    fn create_wakers_list(&self) {
-        let v = self.wakers.load(Ordering::Relaxed);
-        if v.is_null() {
-            let temp = Box::new(SegQueue::new());
-            self.wakers.compare_and_swap(v, Box::into_raw(temp), Ordering::Relaxed);
+impl Fairness for Unfair {
+    const FAIR: bool = false;
+}
+
+/// Unlocking hands the lock over to the oldest waiter, like parking_lot's `unlock_fair` does
+pub struct Fair;
+
+impl private::Sealed for Fair {}
+
+impl Fairness for Fair {
+    const FAIR: bool = true;
+}
+
+/// RawMutex implementor that collects Wakers to wake them up when unlocked
+pub struct FutureRawMutex<R, F = Unfair> where R: RawMutex, F: Fairness {
+    waiters: WaiterQueue,
+    inner: R,
+    #[cfg(feature = "stats")]
+    stats: HoldStats,
+    _fairness: PhantomData<F>,
+}
+
+impl<R, F> FutureRawMutex<R, F> where R: RawMutex, F: Fairness {
+    /// Creates an unlocked raw mutex whose tracing events are tagged with `name`
+    pub const fn named(name: &'static str) -> Self {
+        FutureRawMutex {
+            waiters: WaiterQueue::named(name),
+            inner: R::INIT,
+            #[cfg(feature = "stats")]
+            stats: HoldStats::new(),
+            _fairness: PhantomData,
         }
     }
 
-    fn wake_up(&self) {
-        self.atomic_lock();
-        let v = unsafe { &mut *self.wakers.load(Ordering::Relaxed) };
-        if let Ok(w) = v.pop() {
-            w.wake();
+    /// Creates an unlocked raw mutex whose future acquisitions follow the ordering rules of `class`
+    #[cfg(feature = "lockdep")]
+    pub const fn with_class(class: &'static LockClass) -> Self {
+        FutureRawMutex {
+            waiters: WaiterQueue::with_class(class),
+            inner: R::INIT,
+            #[cfg(feature = "stats")]
+            stats: HoldStats::new(),
+            _fairness: PhantomData,
         }
-        self.atomic_unlock();
     }
 }
 
-impl<R> Drop for FutureRawMutex<R> where R: RawMutex {
-    fn drop(&mut self) {
-        let v = self.wakers.load(Ordering::Relaxed);
-        if !v.is_null() {
-            unsafe { Box::from_raw(v) };
-        }
+#[cfg(feature = "stats")]
+impl<R, F> FutureRawMutex<R, F> where R: RawMutex, F: Fairness {
+    /// Returns a snapshot of the lock statistics
+    pub fn stats(&self) -> LockStats {
+        let mut stats = LockStats::default();
+        self.stats.fill(&mut stats);
+        self.waiters.stats.fill(&mut stats);
+        stats.queue_len = self.waiters.len();
+        stats
     }
 }
 
-unsafe impl<R> RawMutex for FutureRawMutex<R> where R: RawMutex {
+unsafe impl<R, F> RawMutex for FutureRawMutex<R, F> where R: RawMutex, F: Fairness {
     type GuardMarker = R::GuardMarker;
 
-    const INIT: FutureRawMutex<R> = {
+    const INIT: FutureRawMutex<R, F> = {
         FutureRawMutex {
-            locking: AtomicBool::new(false),
-            wakers: AtomicPtr::new(null_mut()),
-            inner: R::INIT
+            waiters: WaiterQueue::new(),
+            inner: R::INIT,
+            #[cfg(feature = "stats")]
+            stats: HoldStats::new(),
+            _fairness: PhantomData,
         }
     };
 
     fn lock(&self) {
-        self.create_wakers_list();
-
         self.inner.lock();
+        #[cfg(feature = "stats")]
+        self.stats.exclusive_acquired();
+        self.waiters.locked();
     }
 
     fn try_lock(&self) -> bool {
-        self.create_wakers_list();
-
-        self.inner.try_lock()
+        // a lock handed over to a future stays locked until that future claims it
+        let locked = self.inner.try_lock() || self.waiters.claim_handoff();
+        if locked {
+            #[cfg(feature = "stats")]
+            self.stats.exclusive_acquired();
+            self.waiters.locked();
+        }
+        locked
     }
 
     fn unlock(&self) {
-        self.inner.unlock();
+        #[cfg(feature = "stats")]
+        self.stats.exclusive_released();
+        self.waiters.released(WaitKind::Exclusive);
+        if F::FAIR {
+            self.waiters.hand_off(|| self.inner.unlock());
+        }
+        else {
+            self.inner.unlock();
 
-        self.wake_up();
+            self.waiters.wake_up();
+        }
+    }
+}
+
+unsafe impl<R, F> RawMutexFair for FutureRawMutex<R, F> where R: RawMutexFair, F: Fairness {
+    fn unlock_fair(&self) {
+        #[cfg(feature = "stats")]
+        self.stats.exclusive_released();
+        self.waiters.released(WaitKind::Exclusive);
+        self.waiters.hand_off(|| self.inner.unlock_fair());
     }
 }
 
 /// Wrapper to use Mutex in Future-style
-pub struct FutureLock<'a, R, T>
+pub struct FutureLock<'a, R, T, F = Unfair>
 where
     R: RawMutex + 'a,
     T: 'a,
+    F: Fairness + 'a,
 {
-    lock: &'a Mutex_<FutureRawMutex<R>, T>,
+    lock: &'a Mutex_<FutureRawMutex<R, F>, T>,
+    waiter: WaitNode,
     _contents: PhantomData<T>,
     _locktype: PhantomData<R>,
 }
 
-impl<'a, R, T> FutureLock<'a, R, T>
+impl<'a, R, T, F> FutureLock<'a, R, T, F>
 where
     R: RawMutex + 'a,
     T: 'a,
+    F: Fairness + 'a,
 {
-    fn new(lock: &'a Mutex_<FutureRawMutex<R>, T>) -> Self {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn new(lock: &'a Mutex_<FutureRawMutex<R, F>, T>) -> Self {
         FutureLock {
             lock,
+            waiter: WaitNode::located(WaitKind::Exclusive),
             _contents: PhantomData,
             _locktype: PhantomData,
         }
     }
 }
 
-impl<'a, R, T> Future for FutureLock<'a, R, T>
+impl<'a, R, T, F> Future for FutureLock<'a, R, T, F>
 where
     R: RawMutex + 'a,
     T: 'a,
+    F: Fairness + 'a,
 {
-    type Output = MutexGuard<'a, FutureRawMutex<R>, T>;
+    type Output = MutexGuard<'a, FutureRawMutex<R, F>, T>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        unsafe { self.lock.raw().atomic_lock(); }
-        match self.lock.try_lock() {
-            Some(read_lock) => {
-                unsafe { self.lock.raw().atomic_unlock(); }
-                Poll::Ready(read_lock)
-            },
-            None => {
-                // Register Waker so we can notified when we can be polled again
-                unsafe { self.lock.raw().register_waker(cx.waker()); }
-                Poll::Pending
-            },
+        let lock = self.lock;
+        unsafe { lock.raw().waiters.poll_acquire(&self.waiter, cx, || lock.try_lock()) }
+    }
+}
+
+impl<'a, R, T, F> Drop for FutureLock<'a, R, T, F>
+where
+    R: RawMutex + 'a,
+    T: 'a,
+    F: Fairness + 'a,
+{
+    fn drop(&mut self) {
+        // a cancelled future must not swallow the wakeup meant for the next waiter
+        unsafe {
+            if self.lock.raw().waiters.cancel(&self.waiter) {
+                // the lock was handed over to us, pass it on
+                self.lock.force_unlock();
+            }
         }
     }
 }
 
 /// Trait to permit FutureLock implementation on wrapped Mutex (not Mutex itself)
-pub trait FutureLockable<R: RawMutex, T> {
+pub trait FutureLockable<R: RawMutex, T, F: Fairness = Unfair> {
     /// Returns the lock without blocking
-    fn future_lock(&self) -> FutureLock<R, T>;
+    fn future_lock(&self) -> FutureLock<'_, R, T, F>;
+
+    /// Tries to take the lock, registering the task to be woken at the next release if it fails
+    ///
+    /// Meant for hand-written `poll` functions that can't store a FutureLock, the task doesn't keep a place in line.
+    fn poll_lock(&self, cx: &mut Context) -> Poll<MutexGuard<'_, FutureRawMutex<R, F>, T>>;
+
+    /// Returns the lock without blocking, giving up after the given timeout
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_lock_for<Tm: Timer>(&self, timeout: Duration, timer: &Tm) -> FutureTimeout<FutureLock<'_, R, T, F>, Tm::Delay> {
+        FutureTimeout::after(self.future_lock(), timeout, timer)
+    }
+
+    /// Returns the lock without blocking, giving up at the given deadline
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_lock_until<Tm: Timer>(&self, deadline: Instant, timer: &Tm) -> FutureTimeout<FutureLock<'_, R, T, F>, Tm::Delay> {
+        FutureTimeout::new(self.future_lock(), timer.delay_until(deadline))
+    }
 }
 
-impl<R: RawMutex, T> FutureLockable<R, T> for Mutex_<FutureRawMutex<R>, T> {
-    fn future_lock(&self) -> FutureLock<R, T> {
+impl<R: RawMutex, T, F: Fairness> FutureLockable<R, T, F> for Mutex_<FutureRawMutex<R, F>, T> {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_lock(&self) -> FutureLock<'_, R, T, F> {
         FutureLock::new(self)
     }
+
+    fn poll_lock(&self, cx: &mut Context) -> Poll<MutexGuard<'_, FutureRawMutex<R, F>, T>> {
+        unsafe { self.raw().waiters.poll_acquire_unpinned(cx, || self.try_lock()) }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
     use std::rc::Rc;
+    use std::future::Future;
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
 
     use tokio::runtime::Runtime as ThreadpoolRuntime;
     use tokio::runtime::current_thread::Runtime as CurrentThreadRuntime;
+    use tokio::future::FutureExt;
+
+    use crate::timeout::tests::ManualTimer;
+    use crate::wakers::tests::counting_waker;
 
-    use super::Mutex;
+    use lock_api::MutexGuard;
+
+    use super::{const_fair_mutex, const_mutex, FairMutex, FutureLockableOwned, Mutex};
+    use super::owned::OwnedMutexGuard;
 
     use super::{FutureLockable};
 
@@ -188,6 +331,9 @@ mod tests {
         static ref CONCURRENT_LOCK: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
     }
 
+    static CONST_LOCK: Mutex<Vec<String>> = const_mutex(Vec::new());
+    static CONCURRENT_CONST_LOCK: FairMutex<Vec<String>> = const_fair_mutex(Vec::new());
+
     #[test]
     fn current_thread_lazy_static() {
         env_logger::try_init().ok();
@@ -309,4 +455,435 @@ mod tests {
         let singleton = CONCURRENT_LOCK.lock();
         assert_eq!(singleton.len(), 1000);
     }
+
+    #[test]
+    fn current_thread_const_static() {
+        env_logger::try_init().ok();
+
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        runtime.block_on(async {
+            let mut v = CONST_LOCK.future_lock().await;
+            v.push(String::from("It works!"));
+            assert!(v.len() == 1 && v[0] == "It works!");
+        });
+    }
+
+    #[test]
+    fn multithread_concurrent_const_static() {
+        env_logger::try_init().ok();
+
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        runtime.block_on(async {
+            // spawn 1000 concurrent futures
+            for i in 0..1000 {
+                tokio::spawn(async move {
+                    let mut v = CONCURRENT_CONST_LOCK.future_lock().await;
+                    v.push(i.to_string());
+                    debug!("{}, pushed {}", v.len(), i);
+                });
+            }
+        });
+        runtime.shutdown_on_idle();
+        let singleton = CONCURRENT_CONST_LOCK.lock();
+        assert_eq!(singleton.len(), 1000);
+    }
+
+    #[test]
+    fn cancelled_waiter_mid_queue() {
+        let lock = Mutex::new(0);
+        let guard = lock.lock();
+
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let (waker3, count3) = counting_waker();
+        let mut f1 = Box::pin(lock.future_lock());
+        let mut f2 = Box::pin(lock.future_lock());
+        let mut f3 = Box::pin(lock.future_lock());
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+        assert!(f3.as_mut().poll(&mut Context::from_waker(&waker3)).is_pending());
+
+        // f1 gets cancelled while queued, the wakeup goes to f2
+        drop(f1);
+        drop(guard);
+        assert_eq!(count1.count(), 0);
+        assert_eq!(count2.count(), 1);
+
+        // f2 gets cancelled after being woken, it must pass the wakeup to f3
+        drop(f2);
+        assert_eq!(count3.count(), 1);
+        assert!(f3.as_mut().poll(&mut Context::from_waker(&waker3)).is_ready());
+    }
+
+    #[test]
+    fn repoll_keeps_single_registration() {
+        let lock = Mutex::new(0);
+        let guard = lock.lock();
+
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let mut f1 = Box::pin(lock.future_lock());
+        let mut f2 = Box::pin(lock.future_lock());
+        for _ in 0..3 {
+            assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        }
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+
+        drop(guard);
+        assert_eq!(count1.count(), 1);
+        assert_eq!(count2.count(), 0);
+
+        // someone else barges in, f1 keeps its place in front of f2
+        let guard = lock.lock();
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        drop(guard);
+        assert_eq!(count1.count(), 2);
+        assert_eq!(count2.count(), 0);
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_ready());
+    }
+
+    #[test]
+    // the guard belongs to our own async Mutex, holding it across awaits is the point
+    #[allow(clippy::await_holding_lock)]
+    fn multithread_timeout_cancelled() {
+        env_logger::try_init().ok();
+
+        let lock = Arc::new(Mutex::new(0));
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        runtime.block_on(async move {
+            let guard = lock.future_lock().await;
+            // these waiters give up while the lock is held
+            for _ in 0..10 {
+                assert!(lock.future_lock().timeout(Duration::from_millis(10)).await.is_err());
+            }
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            let inner = Arc::clone(&lock);
+            tokio::spawn(async move {
+                *inner.future_lock().await += 1;
+                tx.send(()).ok();
+            });
+            drop(guard);
+            rx.timeout(Duration::from_secs(5)).await.expect("live waiter never woken").unwrap();
+            assert_eq!(*lock.future_lock().await, 1);
+        });
+    }
+
+    #[test]
+    fn fair_mutex_fifo_order() {
+        let lock = FairMutex::new(Vec::new());
+        let guard = lock.lock();
+
+        let mut waiters: Vec<_> = (0..10).map(|_| {
+            let (waker, count) = counting_waker();
+            let mut f = Box::pin(lock.future_lock());
+            assert!(f.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+            (f, waker, count)
+        }).collect();
+
+        drop(guard);
+        for i in 0..10 {
+            for (j, (_, _, count)) in waiters.iter().enumerate() {
+                assert_eq!(count.count(), if j <= i { 1 } else { 0 });
+            }
+            // the lock belongs to the woken future, nobody can barge in
+            assert!(lock.try_lock().is_none());
+            let (f, waker, _) = &mut waiters[i];
+            match f.as_mut().poll(&mut Context::from_waker(waker)) {
+                Poll::Ready(mut v) => v.push(i),
+                Poll::Pending => panic!("lock wasn't handed over"),
+            }
+        }
+        assert_eq!(*lock.lock(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn fair_mutex_cancelled_handoff() {
+        let lock = FairMutex::new(0);
+        let guard = lock.lock();
+
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let mut f1 = Box::pin(lock.future_lock());
+        let mut f2 = Box::pin(lock.future_lock());
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+
+        drop(guard);
+        assert_eq!(count1.count(), 1);
+        // f1 owns the lock but goes away without claiming it
+        drop(f1);
+        assert_eq!(count2.count(), 1);
+        assert!(lock.try_lock().is_none());
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_ready());
+        assert!(lock.try_lock().is_some());
+    }
+
+    #[test]
+    fn unlock_fair_hands_over() {
+        let lock = Mutex::new(0);
+        let guard = lock.lock();
+
+        let (waker, count) = counting_waker();
+        let mut f = Box::pin(lock.future_lock());
+        assert!(f.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+
+        MutexGuard::unlock_fair(guard);
+        assert_eq!(count.count(), 1);
+        assert!(lock.try_lock().is_none());
+        assert!(f.as_mut().poll(&mut Context::from_waker(&waker)).is_ready());
+    }
+
+    #[test]
+    fn multithread_concurrent_fair() {
+        env_logger::try_init().ok();
+
+        let lock = Arc::new(FairMutex::new(0));
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        let inner = Arc::clone(&lock);
+        runtime.block_on(async move {
+            for _ in 0..1000 {
+                let lock = Arc::clone(&inner);
+                tokio::spawn(async move {
+                    *lock.future_lock().await += 1;
+                });
+            }
+        });
+        runtime.shutdown_on_idle();
+        assert_eq!(*lock.lock(), 1000);
+    }
+
+    #[test]
+    fn timed_out_waiter_leaves_queue() {
+        let lock = Mutex::new(0);
+        let guard = lock.lock();
+        let timer = ManualTimer::default();
+
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let mut f1 = Box::pin(lock.future_lock_for(Duration::from_millis(10), &timer));
+        let mut f2 = Box::pin(lock.future_lock());
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+
+        timer.fire();
+        match f1.as_mut().poll(&mut Context::from_waker(&waker1)) {
+            Poll::Ready(res) => assert!(res.is_none()),
+            Poll::Pending => panic!("timeout didn't expire"),
+        }
+
+        // f1 is still around, but it isn't in the queue anymore
+        drop(guard);
+        assert_eq!(count1.count(), 0);
+        assert_eq!(count2.count(), 1);
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_ready());
+    }
+
+    #[test]
+    fn timed_lock_acquired() {
+        let lock = Mutex::new(0);
+        let timer = ManualTimer::default();
+        timer.fire();
+
+        // the lock is free, an expired deadline doesn't matter
+        let (waker, _) = counting_waker();
+        let mut f = Box::pin(lock.future_lock_until(Instant::now(), &timer));
+        match f.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(res) => assert!(res.is_some()),
+            Poll::Pending => panic!("free lock not acquired"),
+        };
+    }
+
+    #[test]
+    fn endless_timeout() {
+        let lock = Mutex::new(0);
+        let guard = lock.lock();
+        let timer = ManualTimer::default();
+
+        // the deadline can't be represented, the wait just has no timeout
+        let (waker, count) = counting_waker();
+        let mut f = Box::pin(lock.future_lock_for(Duration::MAX, &timer));
+        assert!(f.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        timer.fire();
+        assert!(f.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+
+        drop(guard);
+        assert_eq!(count.count(), 1);
+        match f.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(res) => assert!(res.is_some()),
+            Poll::Pending => panic!("lock not acquired"),
+        };
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    // the guard belongs to our own async Mutex, holding it across awaits is the point
+    #[allow(clippy::await_holding_lock)]
+    fn multithread_tokio_timer() {
+        use crate::timeout::TokioTimer;
+
+        env_logger::try_init().ok();
+
+        let lock = Arc::new(Mutex::new(0));
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        runtime.block_on(async move {
+            let guard = lock.future_lock().await;
+            assert!(lock.future_lock_for(Duration::from_millis(10), &TokioTimer).await.is_none());
+            drop(guard);
+            assert!(lock.future_lock_for(Duration::from_millis(10), &TokioTimer).await.is_some());
+        });
+    }
+
+    #[test]
+    fn current_thread_owned_guard() {
+        env_logger::try_init().ok();
+
+        struct Holder {
+            guard: OwnedMutexGuard<super::RawMutex_, Vec<String>>,
+        }
+
+        let lock = Arc::new(Mutex::new(Vec::new()));
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        let holder = runtime.block_on(async {
+            Holder { guard: lock.future_lock_owned().await }
+        });
+        // the guard outlives the future that produced it, and moves into a 'static task
+        runtime.spawn(async move {
+            let mut holder = holder;
+            holder.guard.push(String::from("It works!"));
+        });
+        let inner = Arc::clone(&lock);
+        runtime.spawn(async move {
+            let v = inner.future_lock_owned().await;
+            let mut first = OwnedMutexGuard::map(v, |v| &mut v[0]);
+            first.push_str(" Mapped!");
+        });
+        runtime.run().unwrap();
+        assert_eq!(*lock.lock(), vec![String::from("It works! Mapped!")]);
+    }
+
+    #[test]
+    fn cancelled_owned_waiter() {
+        let lock = Arc::new(FairMutex::new(0));
+        let guard = lock.lock();
+
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let mut f1 = Box::pin(lock.future_lock_owned());
+        let mut f2 = Box::pin(lock.future_lock_owned());
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+
+        drop(guard);
+        assert_eq!(count1.count(), 1);
+        drop(f1);
+        assert_eq!(count2.count(), 1);
+        match f2.as_mut().poll(&mut Context::from_waker(&waker2)) {
+            Poll::Ready(mut v) => *v += 1,
+            Poll::Pending => panic!("lock wasn't handed over"),
+        }
+        drop(f2);
+        assert_eq!(*lock.lock(), 1);
+        assert_eq!(Arc::strong_count(&lock), 1);
+    }
+
+    #[test]
+    fn poll_lock_wakes_pollers() {
+        let lock = Mutex::new(0);
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let (waker3, count3) = counting_waker();
+        let mut cx1 = Context::from_waker(&waker1);
+        let guard = lock.lock();
+
+        // polling again with the same task keeps a single registration
+        assert!(lock.poll_lock(&mut cx1).is_pending());
+        assert!(lock.poll_lock(&mut cx1).is_pending());
+        assert!(lock.poll_lock(&mut Context::from_waker(&waker2)).is_pending());
+        let mut f = Box::pin(lock.future_lock());
+        assert!(f.as_mut().poll(&mut Context::from_waker(&waker3)).is_pending());
+
+        // pollers have no place in line, they're all woken together with the oldest future
+        drop(guard);
+        assert_eq!((count1.count(), count2.count(), count3.count()), (1, 1, 1));
+        match lock.poll_lock(&mut cx1) {
+            Poll::Ready(mut v) => *v += 1,
+            Poll::Pending => panic!("free lock not acquired"),
+        }
+        // the second poller went away, its registration has been consumed by the wakeup
+        assert!(f.as_mut().poll(&mut Context::from_waker(&waker3)).is_ready());
+        assert_eq!(count2.count(), 1);
+        assert!(unsafe { lock.raw() }.waiters.is_empty());
+        assert_eq!(*lock.lock(), 1);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_events() {
+        use std::fmt;
+        use std::sync::Mutex as StdMutex;
+
+        use tracing::{Event, Metadata, Subscriber};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+
+        // message and lock name of an event
+        #[derive(Default)]
+        struct Fields(String, String);
+
+        impl Visit for Fields {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                if field.name() == "lock" {
+                    self.1 = value.to_owned();
+                }
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{:?}", value);
+                }
+            }
+        }
+
+        struct Recorder(Arc<StdMutex<Vec<(String, String)>>>);
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn new_span(&self, _: &Attributes) -> Id {
+                Id::from_u64(1)
+            }
+
+            fn record(&self, _: &Id, _: &Record) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event) {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                self.0.lock().unwrap().push((fields.0, fields.1));
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let events = Arc::new(StdMutex::new(Vec::new()));
+        tracing::subscriber::with_default(Recorder(Arc::clone(&events)), || {
+            let lock = super::named_mutex("test", 0);
+            let (waker, _) = counting_waker();
+            let mut cx = Context::from_waker(&waker);
+            let guard = lock.lock();
+            let mut waiter = Box::pin(lock.future_lock());
+            assert!(waiter.as_mut().poll(&mut cx).is_pending());
+            drop(guard);
+            assert!(waiter.as_mut().poll(&mut cx).is_ready());
+        });
+        let events = events.lock().unwrap();
+        let messages: Vec<_> = events.iter().map(|(message, _)| message.as_str()).collect();
+        assert_eq!(messages, ["lock wait started", "lock released", "lock waiter woken", "lock acquired", "lock released"]);
+        assert!(events.iter().all(|(_, lock)| lock == "test"));
+    }
 }
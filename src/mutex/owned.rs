@@ -0,0 +1,248 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::marker::PhantomData;
+use std::future::Future;
+use std::task::{Poll, Context};
+use std::pin::Pin;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::mem;
+
+use lock_api::{Mutex, RawMutex};
+
+use crate::wakers::{WaitKind, WaitNode};
+
+use super::{Fairness, FutureRawMutex, Unfair};
+
+/// Mutex guard that keeps its Mutex alive, so it can be moved around freely
+pub struct OwnedMutexGuard<R, T, F = Unfair>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    lock: Arc<Mutex<FutureRawMutex<R, F>, T>>,
+    data: *mut T,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+unsafe impl<R, T, F> Send for OwnedMutexGuard<R, T, F> where R: RawMutex, R::GuardMarker: Send, T: Send, F: Fairness {}
+unsafe impl<R, T, F> Sync for OwnedMutexGuard<R, T, F> where R: RawMutex, T: Send + Sync, F: Fairness {}
+
+impl<R, T, F> OwnedMutexGuard<R, T, F>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    /// Returns the Mutex this guard belongs to
+    pub fn mutex(s: &Self) -> &Arc<Mutex<FutureRawMutex<R, F>, T>> {
+        &s.lock
+    }
+
+    /// Makes a new guard for a component of the locked data
+    pub fn map<U, M>(s: Self, f: M) -> OwnedMappedMutexGuard<R, T, U, F>
+    where
+        M: FnOnce(&mut T) -> &mut U,
+    {
+        let data = f(unsafe { &mut *s.data }) as *mut U;
+        let lock = unsafe { std::ptr::read(&s.lock) };
+        mem::forget(s);
+        OwnedMappedMutexGuard {
+            lock,
+            data,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Gives up the guard leaving the Mutex locked, whoever keeps the Mutex alive must `force_unlock` it
+    #[cfg(any(feature = "tokio", feature = "futures-io"))]
+    pub(crate) fn leak(s: Self) -> *mut T {
+        let data = s.data;
+        drop(unsafe { std::ptr::read(&s.lock) });
+        mem::forget(s);
+        data
+    }
+}
+
+impl<R, T, F> Deref for OwnedMutexGuard<R, T, F>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<R, T, F> DerefMut for OwnedMutexGuard<R, T, F>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<R, T, F> Drop for OwnedMutexGuard<R, T, F>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    fn drop(&mut self) {
+        unsafe { self.lock.force_unlock(); }
+    }
+}
+
+/// Owned Mutex guard for a component of the locked data
+pub struct OwnedMappedMutexGuard<R, T, U, F = Unfair>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    lock: Arc<Mutex<FutureRawMutex<R, F>, T>>,
+    data: *mut U,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+unsafe impl<R, T, U, F> Send for OwnedMappedMutexGuard<R, T, U, F> where R: RawMutex, R::GuardMarker: Send, T: Send, U: Send, F: Fairness {}
+unsafe impl<R, T, U, F> Sync for OwnedMappedMutexGuard<R, T, U, F> where R: RawMutex, T: Send + Sync, U: Sync, F: Fairness {}
+
+impl<R, T, U, F> OwnedMappedMutexGuard<R, T, U, F>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    /// Makes a new guard for a component of the locked data
+    pub fn map<V, M>(s: Self, f: M) -> OwnedMappedMutexGuard<R, T, V, F>
+    where
+        M: FnOnce(&mut U) -> &mut V,
+    {
+        let data = f(unsafe { &mut *s.data }) as *mut V;
+        let lock = unsafe { std::ptr::read(&s.lock) };
+        mem::forget(s);
+        OwnedMappedMutexGuard {
+            lock,
+            data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<R, T, U, F> Deref for OwnedMappedMutexGuard<R, T, U, F>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        unsafe { &*self.data }
+    }
+}
+
+impl<R, T, U, F> DerefMut for OwnedMappedMutexGuard<R, T, U, F>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<R, T, U, F> Drop for OwnedMappedMutexGuard<R, T, U, F>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    fn drop(&mut self) {
+        unsafe { self.lock.force_unlock(); }
+    }
+}
+
+/// Wrapper to use an Arc'd Mutex in Future-style, resolving to an owned guard
+pub struct FutureLockOwned<R, T, F = Unfair>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    lock: Arc<Mutex<FutureRawMutex<R, F>, T>>,
+    waiter: WaitNode,
+}
+
+impl<R, T, F> FutureLockOwned<R, T, F>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn new(lock: Arc<Mutex<FutureRawMutex<R, F>, T>>) -> Self {
+        FutureLockOwned {
+            lock,
+            waiter: WaitNode::located(WaitKind::Exclusive),
+        }
+    }
+}
+
+impl<R, T, F> Future for FutureLockOwned<R, T, F>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    type Output = OwnedMutexGuard<R, T, F>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let lock = &self.lock;
+        let res = unsafe {
+            lock.raw().waiters.poll_acquire(&self.waiter, cx, || {
+                lock.try_lock().map(|mut guard| {
+                    // the owned guard takes over the unlocking
+                    let data = &mut *guard as *mut T;
+                    mem::forget(guard);
+                    data
+                })
+            })
+        };
+        res.map(|data| OwnedMutexGuard {
+            lock: Arc::clone(lock),
+            data,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<R, T, F> Drop for FutureLockOwned<R, T, F>
+where
+    R: RawMutex,
+    F: Fairness,
+{
+    fn drop(&mut self) {
+        // a cancelled future must not swallow the wakeup meant for the next waiter
+        unsafe {
+            if self.lock.raw().waiters.cancel(&self.waiter) {
+                // the lock was handed over to us, pass it on
+                self.lock.force_unlock();
+            }
+        }
+    }
+}
+
+/// Trait to permit FutureLockOwned implementation on Arc'd Mutex
+pub trait FutureLockableOwned<R: RawMutex, T, F: Fairness = Unfair> {
+    /// Returns the lock without blocking, as a guard that keeps the Mutex alive
+    fn future_lock_owned(&self) -> FutureLockOwned<R, T, F>;
+}
+
+impl<R: RawMutex, T, F: Fairness> FutureLockableOwned<R, T, F> for Arc<Mutex<FutureRawMutex<R, F>, T>> {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_lock_owned(&self) -> FutureLockOwned<R, T, F> {
+        FutureLockOwned::new(Arc::clone(self))
+    }
+}
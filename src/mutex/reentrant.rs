@@ -0,0 +1,360 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Poll, Context};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use lock_api::RawMutex;
+
+use crate::timeout::{FutureTimeout, Timer};
+use crate::wakers::{WaitKind, WaitNode};
+
+use super::{FutureRawMutex, RawMutex_};
+
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Identity of an async task, the owner of a ReentrantMutex
+///
+/// Create one per task and pass it to every lock call made by that task.
+/// It's neither Clone nor Copy, sharing it with another task would make them share the lock too.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct TaskId(NonZeroUsize);
+
+impl TaskId {
+    /// Creates a new, unique, TaskId
+    pub fn new() -> TaskId {
+        let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+        TaskId(NonZeroUsize::new(id).expect("TaskId space exhausted"))
+    }
+}
+
+impl Default for TaskId {
+    fn default() -> TaskId {
+        TaskId::new()
+    }
+}
+
+/// A Future-compatible Mutex that can be locked again by the task that's holding it
+///
+/// Like parking_lot's ReentrantMutex it only gives shared access to its content,
+/// but its owner is the TaskId given when locking instead of the current thread.
+pub struct ReentrantMutex<T: ?Sized> {
+    raw: FutureRawMutex<RawMutex_>,
+    // id of the owning task, 0 when unlocked
+    owner: AtomicUsize,
+    lock_count: AtomicUsize,
+    data: T,
+}
+
+unsafe impl<T: ?Sized + Send> Send for ReentrantMutex<T> {}
+// a misused TaskId can give two threads access at the same time, hence the Sync requirement
+unsafe impl<T: ?Sized + Send + Sync> Sync for ReentrantMutex<T> {}
+
+impl<T> ReentrantMutex<T> {
+    /// Creates a new ReentrantMutex in an unlocked state
+    pub const fn new(val: T) -> ReentrantMutex<T> {
+        ReentrantMutex {
+            raw: <FutureRawMutex<RawMutex_> as RawMutex>::INIT,
+            owner: AtomicUsize::new(0),
+            lock_count: AtomicUsize::new(0),
+            data: val,
+        }
+    }
+
+    /// Consumes this ReentrantMutex, returning the underlying data
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+}
+
+impl<T: ?Sized> ReentrantMutex<T> {
+    /// Returns a mutable reference to the underlying data, no locking is needed as we're borrowed mutably
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+
+    fn lock_internal<F: FnOnce() -> bool>(&self, task: &TaskId, lock: F) -> Option<ReentrantMutexGuard<'_, T>> {
+        let id = task.0.get();
+        // we're holding it already, unless the count just dropped to zero on another thread sharing the TaskId
+        let held = self.owner.load(Ordering::Relaxed) == id && self.lock_count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| match count {
+            0 => None,
+            count => Some(count.checked_add(1).expect("ReentrantMutex lock count overflow")),
+        }).is_ok();
+        if !held {
+            if !lock() {
+                return None;
+            }
+            self.owner.store(id, Ordering::Relaxed);
+            self.lock_count.store(1, Ordering::Relaxed);
+        }
+        Some(ReentrantMutexGuard {
+            mutex: self,
+            marker: PhantomData,
+        })
+    }
+
+    /// Acquires the lock on behalf of the given task, blocking the current thread until it's able to do so
+    pub fn lock(&self, task: &TaskId) -> ReentrantMutexGuard<'_, T> {
+        self.lock_internal(task, || {
+            self.raw.lock();
+            true
+        }).expect("blocking lock can't fail")
+    }
+
+    /// Attempts to acquire the lock on behalf of the given task, without blocking
+    pub fn try_lock(&self, task: &TaskId) -> Option<ReentrantMutexGuard<'_, T>> {
+        self.lock_internal(task, || self.raw.try_lock())
+    }
+
+    /// Returns the lock without blocking, immediately if the given task is holding it already
+    pub fn future_lock<'a>(&'a self, task: &'a TaskId) -> FutureReentrantLock<'a, T> {
+        FutureReentrantLock {
+            mutex: self,
+            task,
+            waiter: WaitNode::new(WaitKind::Exclusive),
+        }
+    }
+
+    /// Returns the lock without blocking, giving up after the given timeout
+    pub fn future_lock_for<'a, Tm: Timer>(&'a self, task: &'a TaskId, timeout: Duration, timer: &Tm) -> FutureTimeout<FutureReentrantLock<'a, T>, Tm::Delay> {
+        FutureTimeout::after(self.future_lock(task), timeout, timer)
+    }
+
+    /// Returns the lock without blocking, giving up at the given deadline
+    pub fn future_lock_until<'a, Tm: Timer>(&'a self, task: &'a TaskId, deadline: Instant, timer: &Tm) -> FutureTimeout<FutureReentrantLock<'a, T>, Tm::Delay> {
+        FutureTimeout::new(self.future_lock(task), timer.delay_until(deadline))
+    }
+
+    fn unlock(&self) {
+        if self.lock_count.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.owner.store(0, Ordering::Relaxed);
+            self.raw.unlock();
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ReentrantMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // there's no TaskId to try the lock with, a new one never owns it
+        match self.try_lock(&TaskId::new()) {
+            Some(guard) => f.debug_struct("ReentrantMutex").field("data", &&*guard).finish(),
+            None => f.debug_struct("ReentrantMutex").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// An RAII guard of a ReentrantMutex, the lock is released when the last guard of the owning task is dropped
+pub struct ReentrantMutexGuard<'a, T: ?Sized> {
+    mutex: &'a ReentrantMutex<T>,
+    marker: PhantomData<(&'a T, <RawMutex_ as RawMutex>::GuardMarker)>,
+}
+
+impl<'a, T: ?Sized> ReentrantMutexGuard<'a, T> {
+    /// Returns a reference to the original ReentrantMutex object
+    pub fn mutex(s: &Self) -> &'a ReentrantMutex<T> {
+        s.mutex
+    }
+}
+
+impl<'a, T: ?Sized> Deref for ReentrantMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.mutex.data
+    }
+}
+
+impl<'a, T: ?Sized> Drop for ReentrantMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// Wrapper to lock a ReentrantMutex in Future-style
+pub struct FutureReentrantLock<'a, T: ?Sized> {
+    mutex: &'a ReentrantMutex<T>,
+    task: &'a TaskId,
+    waiter: WaitNode,
+}
+
+impl<'a, T: ?Sized> Future for FutureReentrantLock<'a, T> {
+    type Output = ReentrantMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let (mutex, task) = (self.mutex, self.task);
+        unsafe { mutex.raw.waiters.poll_acquire(&self.waiter, cx, || mutex.try_lock(task)) }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for FutureReentrantLock<'a, T> {
+    fn drop(&mut self) {
+        // a cancelled future must not swallow the wakeup meant for the next waiter
+        // (the inner Mutex is unfair, so there's nothing else to release)
+        unsafe { self.mutex.raw.waiters.cancel(&self.waiter); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::rc::Rc;
+    use std::task::Context;
+
+    use tokio::runtime::current_thread::Runtime as CurrentThreadRuntime;
+
+    use crate::wakers::tests::counting_waker;
+
+    use super::{ReentrantMutex, TaskId};
+
+    #[test]
+    fn nested_lock() {
+        let mutex = ReentrantMutex::new(RefCell::new(0));
+        let task = TaskId::new();
+        let other = TaskId::new();
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+
+        let outer = mutex.lock(&task);
+        let inner = runtime.block_on(mutex.future_lock(&task));
+        *inner.borrow_mut() += 1;
+        assert!(mutex.try_lock(&other).is_none());
+        drop(outer);
+        // still held by the inner guard
+        assert!(mutex.try_lock(&other).is_none());
+        drop(inner);
+        assert_eq!(*mutex.try_lock(&other).unwrap().borrow(), 1);
+    }
+
+    #[test]
+    fn other_task_waits() {
+        let mutex = ReentrantMutex::new(0);
+        let task = TaskId::new();
+        let other = TaskId::new();
+
+        let outer = mutex.lock(&task);
+        let inner = mutex.lock(&task);
+        let (waker, count) = counting_waker();
+        let mut waiting = Box::pin(mutex.future_lock(&other));
+        assert!(waiting.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        drop(inner);
+        assert_eq!(count.count(), 0);
+        drop(outer);
+        assert_eq!(count.count(), 1);
+        assert!(waiting.as_mut().poll(&mut Context::from_waker(&waker)).is_ready());
+    }
+
+    #[test]
+    fn current_thread_nested_across_await() {
+        env_logger::try_init().ok();
+
+        let mutex = Rc::new(ReentrantMutex::new(RefCell::new(Vec::new())));
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        for i in 0..10 {
+            let mutex = Rc::clone(&mutex);
+            runtime.spawn(async move {
+                let task = TaskId::new();
+                let outer = mutex.future_lock(&task).await;
+                outer.borrow_mut().push(i);
+                tokio::timer::delay_for(std::time::Duration::from_millis(1)).await;
+                let inner = mutex.future_lock(&task).await;
+                // nobody got in between
+                assert_eq!(inner.borrow().last(), Some(&i));
+                inner.borrow_mut().push(i);
+            });
+        }
+        runtime.run().unwrap();
+        let v = mutex.try_lock(&TaskId::new()).unwrap().borrow().clone();
+        assert_eq!(v.len(), 20);
+        assert!(v.chunks(2).all(|c| c[0] == c[1]));
+    }
+
+    #[test]
+    fn threads_nested_across_await() {
+        use std::sync::{Arc, Mutex as StdMutex};
+        use std::thread;
+        use std::time::Duration;
+
+        env_logger::try_init().ok();
+
+        // guards aren't Send without the send_guard feature, so every thread runs its own tasks
+        let mutex = Arc::new(ReentrantMutex::new(StdMutex::new(Vec::new())));
+        let threads: Vec<_> = (0..4).map(|t| {
+            let mutex = Arc::clone(&mutex);
+            thread::spawn(move || {
+                let mut runtime = CurrentThreadRuntime::new().unwrap();
+                for i in 0..5 {
+                    let mutex = Arc::clone(&mutex);
+                    runtime.spawn(async move {
+                        let task = TaskId::new();
+                        let outer = mutex.future_lock(&task).await;
+                        outer.lock().unwrap().push(t * 5 + i);
+                        tokio::timer::delay_for(Duration::from_millis(1)).await;
+                        let inner = mutex.future_lock(&task).await;
+                        // nobody got in between, from this thread or the others
+                        assert_eq!(inner.lock().unwrap().last(), Some(&(t * 5 + i)));
+                        inner.lock().unwrap().push(t * 5 + i);
+                    });
+                }
+                runtime.run().unwrap();
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let v = mutex.try_lock(&TaskId::new()).unwrap().lock().unwrap().clone();
+        assert_eq!(v.len(), 40);
+        assert!(v.chunks(2).all(|c| c[0] == c[1]));
+    }
+
+    #[cfg(feature = "send_guard")]
+    #[test]
+    fn multithread_nested_across_await() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        use tokio::future::FutureExt;
+        use tokio::runtime::Runtime as ThreadpoolRuntime;
+
+        env_logger::try_init().ok();
+
+        let mutex = Arc::new(ReentrantMutex::new(AtomicUsize::new(0)));
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        let inner_mutex = Arc::clone(&mutex);
+        runtime.block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+            for _ in 0..10 {
+                let mutex = Arc::clone(&inner_mutex);
+                let mut tx = tx.clone();
+                tokio::spawn(async move {
+                    let task = TaskId::new();
+                    let outer = mutex.future_lock(&task).await;
+                    let value = outer.load(Ordering::SeqCst);
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    let inner = mutex.future_lock(&task).await;
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    // the whole read-modify-write happened under the lock
+                    inner.store(value + 1, Ordering::SeqCst);
+                    drop(outer);
+                    drop(inner);
+                    tx.send(()).await.ok();
+                });
+            }
+            for _ in 0..10 {
+                rx.recv().timeout(Duration::from_secs(5)).await.expect("task never completed");
+            }
+        });
+        assert_eq!(mutex.try_lock(&TaskId::new()).unwrap().load(Ordering::SeqCst), 10);
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cell::UnsafeCell;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::task::{Poll, Context};
+use std::pin::Pin;
+
+use crate::wakers::{WaiterQueue, WaitKind, WaitNode};
+
+/// no value, nobody initialising
+const EMPTY: u8 = 0;
+/// an initialiser is running
+const RUNNING: u8 = 1;
+/// the value is set
+const READY: u8 = 2;
+
+/// A cell that can be written only once, with an async initialiser
+///
+/// Concurrent callers of `get_or_init` wait for the one running its initialiser,
+/// if that one gets cancelled, fails or panics, one of the waiting callers runs its own initialiser instead.
+pub struct OnceCell<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+    waiters: WaiterQueue,
+}
+
+unsafe impl<T: Send> Send for OnceCell<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    /// Creates a new empty OnceCell
+    pub const fn new() -> OnceCell<T> {
+        OnceCell {
+            state: AtomicU8::new(EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            waiters: WaiterQueue::new(),
+        }
+    }
+
+    /// Returns the value, if set
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == READY {
+            Some(unsafe { self.get_unchecked() })
+        }
+        else {
+            None
+        }
+    }
+
+    /// Returns the value mutably, if set, no locking is needed as we're borrowed mutably
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if *self.state.get_mut() == READY {
+            Some(unsafe { (*self.value.get()).assume_init_mut() })
+        }
+        else {
+            None
+        }
+    }
+
+    /// Sets the value, giving it back if the cell is already set or being initialised
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self.state.compare_exchange(EMPTY, RUNNING, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return Err(value);
+        }
+        unsafe { self.complete(value); }
+        Ok(())
+    }
+
+    /// Consumes the OnceCell, returning the value, if set
+    pub fn into_inner(mut self) -> Option<T> {
+        if *self.state.get_mut() == READY {
+            *self.state.get_mut() = EMPTY;
+            Some(unsafe { (*self.value.get()).assume_init_read() })
+        }
+        else {
+            None
+        }
+    }
+
+    /// Returns the value, running the given initialiser if the cell is empty, without blocking
+    pub fn get_or_init<F, Fut>(&self, init: F) -> FutureGetOrInit<'_, T, F, Fut>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        FutureGetOrInit {
+            inner: FutureInit::new(self, init, Ok),
+        }
+    }
+
+    /// Returns the value, running the given initialiser if the cell is empty, without blocking
+    ///
+    /// If the initialiser fails the error is returned and the cell stays empty.
+    pub fn get_or_try_init<F, Fut, E>(&self, init: F) -> FutureGetOrTryInit<'_, T, E, F, Fut>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        FutureGetOrTryInit {
+            inner: FutureInit::new(self, init, |res| res),
+        }
+    }
+
+    /// # Safety
+    /// the value must be set
+    unsafe fn get_unchecked(&self) -> &T {
+        (*self.value.get()).assume_init_ref()
+    }
+
+    /// # Safety
+    /// the cell must be RUNNING, and the caller the one that made it so
+    unsafe fn complete(&self, value: T) {
+        (*self.value.get()).write(value);
+        self.state.store(READY, Ordering::SeqCst);
+        self.waiters.notify_all();
+    }
+
+    /// Called by the initialiser that failed, panicked or got cancelled, to let another one run
+    fn abort(&self) {
+        self.state.store(EMPTY, Ordering::SeqCst);
+        self.waiters.notify_all();
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> OnceCell<T> {
+        OnceCell::new()
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { (*self.value.get()).assume_init_drop(); }
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OnceCell").field("value", &self.get()).finish()
+    }
+}
+
+/// Shared by get_or_init and get_or_try_init, convert turns the initialiser's output into a Result
+struct FutureInit<'a, T, E, F, Fut>
+where
+    Fut: Future,
+{
+    cell: &'a OnceCell<T>,
+    init: Option<F>,
+    convert: fn(Fut::Output) -> Result<T, E>,
+    running: Option<Fut>,
+    // set once we've made the cell RUNNING, until we complete or abort it
+    owner: bool,
+    waiter: WaitNode,
+}
+
+impl<'a, T, E, F, Fut> FutureInit<'a, T, E, F, Fut>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future,
+{
+    fn new(cell: &'a OnceCell<T>, init: F, convert: fn(Fut::Output) -> Result<T, E>) -> Self {
+        FutureInit {
+            cell,
+            init: Some(init),
+            convert,
+            running: None,
+            owner: false,
+            waiter: WaitNode::new(WaitKind::Shared),
+        }
+    }
+
+    fn poll_init(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<&'a T, E>> {
+        // running and waiter are structurally pinned, running is only dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        let cell = this.cell;
+        loop {
+            if let Some(running) = this.running.as_mut() {
+                let res = match unsafe { Pin::new_unchecked(running) }.poll(cx) {
+                    Poll::Ready(res) => (this.convert)(res),
+                    Poll::Pending => return Poll::Pending,
+                };
+                this.running = None;
+                this.owner = false;
+                return Poll::Ready(match res {
+                    Ok(value) => unsafe {
+                        cell.complete(value);
+                        Ok(cell.get_unchecked())
+                    },
+                    Err(e) => {
+                        cell.abort();
+                        Err(e)
+                    },
+                });
+            }
+            match cell.state.load(Ordering::Acquire) {
+                READY => {
+                    unsafe { cell.waiters.leave(&this.waiter); }
+                    return Poll::Ready(Ok(unsafe { cell.get_unchecked() }));
+                },
+                EMPTY => {
+                    if cell.state.compare_exchange(EMPTY, RUNNING, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                        unsafe { cell.waiters.leave(&this.waiter); }
+                        let init = this.init.take().expect("FutureInit polled after completion");
+                        // if init panics, dropping us lets somebody else try
+                        this.owner = true;
+                        this.running = Some(init());
+                    }
+                },
+                _ => match unsafe { cell.waiters.poll_notified(&this.waiter, cx) } {
+                    Poll::Ready(()) => {},
+                    // the initialiser could have finished while we were registering
+                    Poll::Pending if cell.state.load(Ordering::SeqCst) != RUNNING => {},
+                    Poll::Pending => return Poll::Pending,
+                },
+            }
+        }
+    }
+}
+
+impl<'a, T, E, F, Fut> Drop for FutureInit<'a, T, E, F, Fut>
+where
+    Fut: Future,
+{
+    fn drop(&mut self) {
+        if self.owner {
+            // cancelled or panicked initialiser, let somebody else try
+            self.running = None;
+            self.cell.abort();
+        }
+        unsafe { self.cell.waiters.leave(&self.waiter); }
+    }
+}
+
+/// Wrapper to get the value of a OnceCell in Future-style, initialising it if needed
+pub struct FutureGetOrInit<'a, T, F, Fut>
+where
+    Fut: Future<Output = T>,
+{
+    inner: FutureInit<'a, T, Infallible, F, Fut>,
+}
+
+impl<'a, T, F, Fut> Future for FutureGetOrInit<'a, T, F, Fut>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = T>,
+{
+    type Output = &'a T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let inner = unsafe { self.map_unchecked_mut(|s| &mut s.inner) };
+        inner.poll_init(cx).map(|res| match res {
+            Ok(value) => value,
+            Err(e) => match e {},
+        })
+    }
+}
+
+/// Wrapper to get the value of a OnceCell in Future-style, initialising it with a fallible initialiser if needed
+pub struct FutureGetOrTryInit<'a, T, E, F, Fut>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
+    inner: FutureInit<'a, T, E, F, Fut>,
+}
+
+impl<'a, T, E, F, Fut> Future for FutureGetOrTryInit<'a, T, E, F, Fut>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    type Output = Result<&'a T, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let inner = unsafe { self.map_unchecked_mut(|s| &mut s.inner) };
+        inner.poll_init(cx)
+    }
+}
+
+/// Boxed async initialiser, the default for Lazy so it can be named in statics
+pub type LazyInit<T> = fn() -> Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// A value initialised asynchronously on first access
+///
+/// The initialiser runs again if the future that was running it gets cancelled.
+pub struct Lazy<T, F = LazyInit<T>> {
+    cell: OnceCell<T>,
+    init: F,
+}
+
+impl<T, F> Lazy<T, F> {
+    /// Creates a new Lazy value with the given initialiser
+    pub const fn new(init: F) -> Lazy<T, F> {
+        Lazy {
+            cell: OnceCell::new(),
+            init,
+        }
+    }
+
+    /// Returns the value, if already initialised
+    pub fn get(&self) -> Option<&T> {
+        self.cell.get()
+    }
+}
+
+impl<T, F, Fut> Lazy<T, F>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = T>,
+{
+    /// Returns the value, initialising it if needed, without blocking
+    pub fn force(&self) -> FutureGetOrInit<'_, T, &F, Fut> {
+        self.cell.get_or_init(&self.init)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lazy").field("value", &self.get()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+
+    use tokio::runtime::Runtime as ThreadpoolRuntime;
+    use tokio::future::FutureExt;
+
+    use crate::timeout::Timer;
+    use crate::timeout::tests::ManualTimer;
+    use crate::wakers::tests::counting_waker;
+
+    use super::{Lazy, OnceCell};
+
+    static LAZY: Lazy<Vec<String>> = Lazy::new(|| Box::pin(async {
+        tokio::timer::delay_for(Duration::from_millis(1)).await;
+        vec![String::from("It works!")]
+    }));
+
+    #[test]
+    fn set_and_get() {
+        let cell = OnceCell::new();
+        assert_eq!(cell.get(), None);
+        assert_eq!(cell.set(1), Ok(()));
+        assert_eq!(cell.set(2), Err(2));
+        assert_eq!(cell.get(), Some(&1));
+        assert_eq!(cell.into_inner(), Some(1));
+    }
+
+    #[test]
+    fn waiters_share_initialiser() {
+        let cell = OnceCell::new();
+        let timer = ManualTimer::default();
+        let (waker, count) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let delay = timer.delay_until(Instant::now());
+        let mut first = Box::pin(cell.get_or_init(|| async move {
+            delay.await;
+            1
+        }));
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        let mut second = Box::pin(cell.get_or_init(|| async { 2 }));
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        timer.fire();
+        match first.as_mut().poll(&mut cx) {
+            Poll::Ready(value) => assert_eq!(*value, 1),
+            Poll::Pending => panic!("initialiser didn't complete"),
+        }
+        assert_eq!(count.count(), 1);
+        match second.as_mut().poll(&mut cx) {
+            Poll::Ready(value) => assert_eq!(*value, 1),
+            Poll::Pending => panic!("waiter not released"),
+        }
+    }
+
+    #[test]
+    fn cancelled_initialiser_retried() {
+        let cell = OnceCell::new();
+        let (waker, count) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut first = Box::pin(cell.get_or_init(std::future::pending::<u32>));
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        let mut second = Box::pin(cell.get_or_init(|| async { 2 }));
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        drop(first);
+        assert_eq!(count.count(), 1);
+        match second.as_mut().poll(&mut cx) {
+            Poll::Ready(value) => assert_eq!(*value, 2),
+            Poll::Pending => panic!("second initialiser didn't run"),
+        }
+    }
+
+    #[test]
+    fn panicked_initialiser_retried() {
+        let cell = OnceCell::new();
+        let timer = ManualTimer::default();
+        let (waker, count) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // panicking before returning its future
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            Box::pin(cell.get_or_init(|| -> std::future::Ready<u32> { panic!("init failed") })).as_mut().poll(&mut cx)
+        }));
+        assert!(res.is_err());
+
+        // panicking while polled, with somebody waiting
+        let delay = timer.delay_until(Instant::now());
+        let mut first = Box::pin(cell.get_or_init(|| async move {
+            delay.await;
+            panic!("init failed")
+        }));
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        let mut second = Box::pin(cell.get_or_init(|| async { 2 }));
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        timer.fire();
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| first.as_mut().poll(&mut cx))).is_err());
+        let woken = count.count();
+        drop(first);
+        assert_eq!(count.count(), woken + 1);
+        match second.as_mut().poll(&mut cx) {
+            Poll::Ready(value) => assert_eq!(*value, 2),
+            Poll::Pending => panic!("second initialiser didn't run"),
+        }
+    }
+
+    #[test]
+    fn failed_initialiser_retried() {
+        let cell = OnceCell::new();
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        let res: Result<&u32, &str> = runtime.block_on(cell.get_or_try_init(|| async { Err("nope") }));
+        assert_eq!(res, Err("nope"));
+        assert_eq!(cell.get(), None);
+        let res: Result<&u32, &str> = runtime.block_on(cell.get_or_try_init(|| async { Ok(3) }));
+        assert_eq!(res, Ok(&3));
+    }
+
+    #[test]
+    fn multithread_initialised_once() {
+        env_logger::try_init().ok();
+
+        let cell = Arc::new(OnceCell::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        runtime.block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+            for i in 0..10 {
+                let cell = Arc::clone(&cell);
+                let runs = Arc::clone(&runs);
+                let mut tx = tx.clone();
+                tokio::spawn(async move {
+                    let value = cell.get_or_init(|| async move {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        tokio::timer::delay_for(Duration::from_millis(10)).await;
+                        i
+                    }).await;
+                    tx.send(*value).await.ok();
+                });
+            }
+            let first = rx.recv().timeout(Duration::from_secs(5)).await.expect("task never completed");
+            for _ in 1..10 {
+                assert_eq!(rx.recv().timeout(Duration::from_secs(5)).await.expect("task never completed"), first);
+            }
+            assert_eq!(runs.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn lazy_static() {
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        assert_eq!(LAZY.get(), None);
+        assert_eq!(*runtime.block_on(LAZY.force()), vec![String::from("It works!")]);
+        assert!(LAZY.get().is_some());
+    }
+}
@@ -5,85 +5,113 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use std::task::Waker;
-use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
-use std::ptr::null_mut;
-use crossbeam_queue::SegQueue;
-
+/// Owned guards and their Futures
+pub mod owned;
 /// FutureRead module
 pub mod read;
 /// FutureUpgradableRead module
 pub mod upgradable_read;
+/// FutureUpgrade module
+pub mod upgrade;
 /// FutureWrite module
 pub mod write;
 
+/// Traits to permit owned Futures implementation on Arc'd RwLock
+pub use owned::{FutureReadableOwned, FutureUpgradableReadableOwned, FutureWriteableOwned};
 /// Trait to permit FutureRead implementation on wrapped RwLock (not RwLock itself)
 pub use read::FutureReadable;
 /// Trait to permit FutureUpgradableRead implementation on wrapped RwLock (not RwLock itself)
 pub use upgradable_read::FutureUpgradableReadable;
+/// Upgrades an upgradable-read guard to a write guard without blocking
+pub use upgrade::future_upgrade;
 /// Trait to permit FutureWrite implementation on wrapped RwLock (not RwLock itself)
 pub use write::FutureWriteable;
 
-use lock_api::{RwLock as RwLock_, RawRwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lock_api::{RwLock as RwLock_, RawRwLock, RawRwLockDowngrade};
+
+#[cfg(not(feature = "send_guard"))]
+pub(crate) use parking_lot::RawRwLock as RawRwLock_;
+#[cfg(feature = "send_guard")]
+pub(crate) use crate::send_guard::RawRwLock as RawRwLock_;
 
-use parking_lot::RawRwLock as RawRwLock_;
+use crate::wakers::{WaiterQueue, WaitKind};
+#[cfg(feature = "stats")]
+use crate::stats::{HoldStats, LockStats};
+#[cfg(feature = "lockdep")]
+use crate::lockdep::LockClass;
 
 /// a Future-compatible parking_lot::RwLock
 pub type RwLock<T> = RwLock_<FutureRawRwLock<RawRwLock_>, T>;
 
+/// Creates a new RwLock in a const context, so it can be declared as a plain static
+pub const fn const_rwlock<T>(val: T) -> RwLock<T> {
+    RwLock::const_new(<FutureRawRwLock<RawRwLock_> as RawRwLock>::INIT, val)
+}
+
+/// Creates a new RwLock whose tracing events are tagged with `name`, in a const context too
+pub const fn named_rwlock<T>(name: &'static str, val: T) -> RwLock<T> {
+    RwLock::const_new(FutureRawRwLock::named(name), val)
+}
+
+/// Creates a new RwLock whose future acquisitions follow the ordering rules of `class`, in a const context too
+#[cfg(feature = "lockdep")]
+pub const fn rwlock_with_class<T>(class: &'static LockClass, val: T) -> RwLock<T> {
+    RwLock::const_new(FutureRawRwLock::with_class(class), val)
+}
+
 /// RawRwLock implementor that collects Wakers to wake them up when unlocked
 pub struct FutureRawRwLock<R: RawRwLock> {
-    locking: AtomicBool,
-    wakers: AtomicPtr<SegQueue<Waker>>,
+    waiters: WaiterQueue,
     inner: R,
+    // set while a FutureUpgrade waits for the readers to leave, new readers must wait too
+    upgrading: AtomicBool,
+    #[cfg(feature = "stats")]
+    stats: HoldStats,
 }
 
-impl<R> FutureRawRwLock<R> where R: RawRwLock {
-    // this is needed to avoid sequences like that:
-    // * thread 1 gains lock
-    // * thread 2 try lock
-    // * thread 1 unlock
-    // * thread 2 register waker
-    // this creates a situation similar to a deadlock, where the future isn't waked up by nobody
-    fn atomic_lock(&self) {
-        while self.locking.compare_and_swap(false, true, Ordering::Relaxed) {}
-    }
-
-    fn atomic_unlock(&self) {
-        self.locking.store(false, Ordering::Relaxed);
-    }
-
-    fn register_waker(&self, waker: &Waker) {
-        let v = unsafe { &mut *self.wakers.load(Ordering::Relaxed) };
-        v.push(waker.clone());
-        // implicitly unlock
-        self.atomic_unlock();
+impl<R: RawRwLock> FutureRawRwLock<R> {
+    /// Creates an unlocked raw rwlock whose tracing events are tagged with `name`
+    pub const fn named(name: &'static str) -> Self {
+        FutureRawRwLock {
+            waiters: WaiterQueue::named(name),
+            inner: R::INIT,
+            upgrading: AtomicBool::new(false),
+            #[cfg(feature = "stats")]
+            stats: HoldStats::new(),
+        }
     }
 
-    fn create_wakers_list(&self) {
-        let v = self.wakers.load(Ordering::Relaxed);
-        if v.is_null() {
-            let temp = Box::new(SegQueue::new());
-            self.wakers.compare_and_swap(v, Box::into_raw(temp), Ordering::Relaxed);
+    /// Creates an unlocked raw rwlock whose future acquisitions follow the ordering rules of `class`
+    #[cfg(feature = "lockdep")]
+    pub const fn with_class(class: &'static LockClass) -> Self {
+        FutureRawRwLock {
+            waiters: WaiterQueue::with_class(class),
+            inner: R::INIT,
+            upgrading: AtomicBool::new(false),
+            #[cfg(feature = "stats")]
+            stats: HoldStats::new(),
         }
     }
+}
 
-    fn wake_up(&self) {
-        self.atomic_lock();
-        let v = unsafe { &mut *self.wakers.load(Ordering::Relaxed) };
-        if let Ok(w) = v.pop() {
-            w.wake();
-        }
-        self.atomic_unlock();
+impl<R: RawRwLock> FutureRawRwLock<R> {
+    // future readers wait behind a pending upgrade, so that new ones can't starve it
+    pub(crate) fn upgrade_pending(&self) -> bool {
+        self.upgrading.load(Ordering::Relaxed)
     }
 }
 
-impl<R> Drop for FutureRawRwLock<R> where R: RawRwLock {
-    fn drop(&mut self) {
-        let v = self.wakers.load(Ordering::Relaxed);
-        if !v.is_null() {
-            unsafe { Box::from_raw(v) };
-        }
+#[cfg(feature = "stats")]
+impl<R: RawRwLock> FutureRawRwLock<R> {
+    /// Returns a snapshot of the lock statistics
+    pub fn stats(&self) -> LockStats {
+        let mut stats = LockStats::default();
+        self.stats.fill(&mut stats);
+        self.waiters.stats.fill(&mut stats);
+        stats.queue_len = self.waiters.len();
+        stats
     }
 }
 
@@ -92,46 +120,75 @@ unsafe impl<R> RawRwLock for FutureRawRwLock<R> where R: RawRwLock {
 
     const INIT: FutureRawRwLock<R> = {
         FutureRawRwLock {
-            locking: AtomicBool::new(false),
-            wakers: AtomicPtr::new(null_mut()),
-            inner: R::INIT
+            waiters: WaiterQueue::new(),
+            inner: R::INIT,
+            upgrading: AtomicBool::new(false),
+            #[cfg(feature = "stats")]
+            stats: HoldStats::new(),
         }
     };
 
     fn lock_shared(&self) {
-        self.create_wakers_list();
-
         self.inner.lock_shared();
+        #[cfg(feature = "stats")]
+        self.stats.shared_acquired();
+        self.waiters.locked();
     }
 
     fn try_lock_shared(&self) -> bool {
-        self.create_wakers_list();
-
-        self.inner.try_lock_shared()
+        let locked = self.inner.try_lock_shared();
+        if locked {
+            #[cfg(feature = "stats")]
+            self.stats.shared_acquired();
+            self.waiters.locked();
+        }
+        locked
     }
 
     fn unlock_shared(&self) {
+        #[cfg(feature = "stats")]
+        self.stats.shared_released();
+        self.waiters.released(WaitKind::Shared);
         self.inner.unlock_shared();
 
-        self.wake_up();
+        self.waiters.wake_up();
     }
 
     fn lock_exclusive(&self) {
-        self.create_wakers_list();
-
         self.inner.lock_exclusive();
+        #[cfg(feature = "stats")]
+        self.stats.exclusive_acquired();
+        self.waiters.locked();
     }
 
     fn try_lock_exclusive(&self) -> bool {
-        self.create_wakers_list();
-
-        self.inner.try_lock_exclusive()
+        let locked = self.inner.try_lock_exclusive();
+        if locked {
+            #[cfg(feature = "stats")]
+            self.stats.exclusive_acquired();
+            self.waiters.locked();
+        }
+        locked
     }
 
     fn unlock_exclusive(&self) {
+        #[cfg(feature = "stats")]
+        self.stats.exclusive_released();
+        self.waiters.released(WaitKind::Exclusive);
         self.inner.unlock_exclusive();
 
-        self.wake_up();
+        self.waiters.wake_up();
+    }
+}
+
+unsafe impl<R> RawRwLockDowngrade for FutureRawRwLock<R> where R: RawRwLockDowngrade {
+    fn downgrade(&self) {
+        #[cfg(feature = "stats")]
+        self.stats.downgraded();
+        self.inner.downgrade();
+
+        // the lock is readable now, but still not writeable
+        self.waiters.wake_readers(true);
     }
 }
 
@@ -139,11 +196,22 @@ unsafe impl<R> RawRwLock for FutureRawRwLock<R> where R: RawRwLock {
 mod tests {
     use std::sync::Arc;
     use std::rc::Rc;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
 
     use tokio::runtime::Runtime as ThreadpoolRuntime;
     use tokio::runtime::current_thread::Runtime as CurrentThreadRuntime;
 
-    use super::{RwLock, FutureReadable, FutureWriteable};
+    use crate::timeout::tests::ManualTimer;
+    use crate::wakers::tests::counting_waker;
+
+    use lock_api::{RwLockUpgradableReadGuard, RwLockWriteGuard};
+
+    use super::{const_rwlock, RwLock, FutureReadable, FutureUpgradableReadable, FutureWriteable, future_upgrade};
+    use super::{FutureReadableOwned, FutureUpgradableReadableOwned, FutureWriteableOwned};
+    use super::owned::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard};
 
     use lazy_static::lazy_static;
 
@@ -155,6 +223,9 @@ mod tests {
         static ref CONCURRENT_LOCK: Arc<RwLock<Vec<String>>> = Arc::new(RwLock::new(Vec::new()));
     }
 
+    static CONST_LOCK: RwLock<Vec<String>> = const_rwlock(Vec::new());
+    static CONCURRENT_CONST_LOCK: RwLock<Vec<String>> = const_rwlock(Vec::new());
+
     #[test]
     fn current_thread_lazy_static() {
         env_logger::try_init().ok();
@@ -312,4 +383,389 @@ mod tests {
         let singleton = CONCURRENT_LOCK.read();
         assert_eq!(singleton.len(), 100);
     }
+
+    #[test]
+    fn current_thread_const_static() {
+        env_logger::try_init().ok();
+
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        runtime.block_on(async {
+            {
+                let mut v = CONST_LOCK.future_write().await;
+                v.push(String::from("It works!"));
+            }
+
+            let v = CONST_LOCK.future_read().await;
+            assert!(v.len() == 1 && v[0] == "It works!");
+        });
+    }
+
+    #[test]
+    fn multithread_concurrent_const_static() {
+        env_logger::try_init().ok();
+
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        runtime.block_on(async {
+            // spawn 100 concurrent futures
+            for i in 0..100 {
+                tokio::spawn(async move {
+                    {
+                        let mut v = CONCURRENT_CONST_LOCK.future_write().await;
+                        v.push(i.to_string());
+                    }
+
+                    let v = CONCURRENT_CONST_LOCK.future_read().await;
+                    info!("{}, pushed {}", v.len(), i);
+                });
+            }
+        });
+        runtime.shutdown_on_idle();
+        let singleton = CONCURRENT_CONST_LOCK.read();
+        assert_eq!(singleton.len(), 100);
+    }
+
+    #[test]
+    fn cancelled_waiter_mid_queue() {
+        let lock = RwLock::new(0);
+        let guard = lock.write();
+
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let (waker3, count3) = counting_waker();
+        let mut f1 = Box::pin(lock.future_read());
+        let mut f2 = Box::pin(lock.future_upgradable_read());
+        let mut f3 = Box::pin(lock.future_write());
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+        assert!(f3.as_mut().poll(&mut Context::from_waker(&waker3)).is_pending());
+
+        // f1 gets cancelled while queued, the wakeup goes to f2
+        drop(f1);
+        drop(guard);
+        assert_eq!(count1.count(), 0);
+        assert_eq!(count2.count(), 1);
+
+        // f2 gets cancelled after being woken, it must pass the wakeup to f3
+        drop(f2);
+        assert_eq!(count3.count(), 1);
+        assert!(f3.as_mut().poll(&mut Context::from_waker(&waker3)).is_ready());
+    }
+
+    #[test]
+    fn writer_release_wakes_all_readers() {
+        let lock = RwLock::new(0);
+        let guard = lock.write();
+
+        let readers: Vec<_> = (0..50).map(|_| (Box::pin(lock.future_read()), counting_waker())).collect();
+        let mut readers: Vec<_> = readers.into_iter().map(|(mut f, (waker, count))| {
+            assert!(f.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+            (f, waker, count)
+        }).collect();
+        let (writer_waker, writer_count) = counting_waker();
+        let mut writer = Box::pin(lock.future_write());
+        assert!(writer.as_mut().poll(&mut Context::from_waker(&writer_waker)).is_pending());
+
+        drop(guard);
+        let mut guards = Vec::new();
+        for (f, waker, count) in readers.iter_mut() {
+            assert_eq!(count.count(), 1);
+            match f.as_mut().poll(&mut Context::from_waker(waker)) {
+                Poll::Ready(g) => guards.push(g),
+                Poll::Pending => panic!("woken reader couldn't read"),
+            }
+        }
+        assert_eq!(writer_count.count(), 0);
+
+        drop(guards);
+        assert_eq!(writer_count.count(), 1);
+        assert!(writer.as_mut().poll(&mut Context::from_waker(&writer_waker)).is_ready());
+    }
+
+    #[test]
+    fn reader_batch_stops_at_writer() {
+        let lock = RwLock::new(0);
+        let guard = lock.write();
+
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let (waker3, count3) = counting_waker();
+        let (waker4, count4) = counting_waker();
+        let mut f1 = Box::pin(lock.future_read());
+        let mut f2 = Box::pin(lock.future_upgradable_read());
+        let mut f3 = Box::pin(lock.future_write());
+        let mut f4 = Box::pin(lock.future_read());
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+        assert!(f3.as_mut().poll(&mut Context::from_waker(&waker3)).is_pending());
+        assert!(f4.as_mut().poll(&mut Context::from_waker(&waker4)).is_pending());
+
+        drop(guard);
+        assert_eq!(count1.count(), 1);
+        assert_eq!(count2.count(), 1);
+        assert_eq!(count3.count(), 0);
+        assert_eq!(count4.count(), 0);
+    }
+
+    #[test]
+    fn downgrade_wakes_readers() {
+        let lock = RwLock::new(0);
+        let guard = lock.write();
+
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let (waker3, count3) = counting_waker();
+        let (waker4, count4) = counting_waker();
+        let mut f1 = Box::pin(lock.future_read());
+        let mut f2 = Box::pin(lock.future_write());
+        let mut f3 = Box::pin(lock.future_read());
+        let mut f4 = Box::pin(lock.future_upgradable_read());
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+        assert!(f3.as_mut().poll(&mut Context::from_waker(&waker3)).is_pending());
+        assert!(f4.as_mut().poll(&mut Context::from_waker(&waker4)).is_pending());
+
+        let guard = RwLockWriteGuard::downgrade(guard);
+        assert_eq!(count1.count(), 1);
+        assert_eq!(count2.count(), 0);
+        assert_eq!(count3.count(), 1);
+        assert_eq!(count4.count(), 1);
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_ready());
+        assert!(f3.as_mut().poll(&mut Context::from_waker(&waker3)).is_ready());
+        assert!(f4.as_mut().poll(&mut Context::from_waker(&waker4)).is_ready());
+
+        // the writer is woken only when the last reader goes away
+        drop(guard);
+        assert_eq!(count2.count(), 1);
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_ready());
+    }
+
+    #[test]
+    fn downgrade_to_upgradable_wakes_readers() {
+        let lock = RwLock::new(0);
+        let guard = lock.write();
+
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let mut f1 = Box::pin(lock.future_upgradable_read());
+        let mut f2 = Box::pin(lock.future_read());
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+
+        let guard = RwLockWriteGuard::downgrade_to_upgradable(guard);
+        assert_eq!(count1.count(), 0);
+        assert_eq!(count2.count(), 1);
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_ready());
+
+        let guard = RwLockUpgradableReadGuard::downgrade(guard);
+        assert_eq!(count1.count(), 1);
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_ready());
+        drop(guard);
+    }
+
+    #[test]
+    fn future_upgrade_waits_for_readers() {
+        let lock = RwLock::new(0);
+        let upgradable = lock.upgradable_read();
+        let reader = lock.read();
+
+        // a writer queued before the upgrade can't get in anyway
+        let (writer_waker, writer_count) = counting_waker();
+        let mut writer = Box::pin(lock.future_write());
+        assert!(writer.as_mut().poll(&mut Context::from_waker(&writer_waker)).is_pending());
+
+        let (waker, count) = counting_waker();
+        let mut upgrade = Box::pin(future_upgrade(upgradable));
+        assert!(upgrade.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+
+        drop(reader);
+        assert_eq!(count.count(), 1);
+        assert_eq!(writer_count.count(), 0);
+        match upgrade.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(mut v) => *v += 1,
+            Poll::Pending => panic!("upgrade didn't happen"),
+        }
+        assert_eq!(writer_count.count(), 1);
+        match writer.as_mut().poll(&mut Context::from_waker(&writer_waker)) {
+            Poll::Ready(v) => assert_eq!(*v, 1),
+            Poll::Pending => panic!("writer didn't get the lock"),
+        };
+    }
+
+    #[test]
+    fn future_upgrade_not_starved_by_readers() {
+        let lock = RwLock::new(0);
+        let upgradable = lock.upgradable_read();
+        let reader = lock.read();
+
+        let (waker, count) = counting_waker();
+        let mut upgrade = Box::pin(future_upgrade(upgradable));
+        assert!(upgrade.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+
+        // readers keep arriving, but they can't keep the lock readable while the upgrade waits
+        let mut readers: Vec<_> = (0..10).map(|_| (Box::pin(lock.future_read()), counting_waker())).collect();
+        for (f, (waker, _)) in readers.iter_mut() {
+            assert!(f.as_mut().poll(&mut Context::from_waker(waker)).is_pending());
+        }
+        let (poll_waker, _) = counting_waker();
+        assert!(lock.poll_read(&mut Context::from_waker(&poll_waker)).is_pending());
+
+        drop(reader);
+        assert_eq!(count.count(), 1);
+        match upgrade.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(mut v) => *v += 1,
+            Poll::Pending => panic!("upgrade starved by readers"),
+        }
+        for (f, (waker, count)) in readers.iter_mut() {
+            assert_eq!(count.count(), 1);
+            match f.as_mut().poll(&mut Context::from_waker(waker)) {
+                Poll::Ready(v) => assert_eq!(*v, 1),
+                Poll::Pending => panic!("reader not let in after the upgrade"),
+            }
+        }
+    }
+
+    #[test]
+    fn sync_readers_ignore_pending_upgrade() {
+        let lock = RwLock::new(0);
+        let upgradable = lock.upgradable_read();
+        let reader = lock.read();
+
+        let (waker, _) = counting_waker();
+        let mut upgrade = Box::pin(future_upgrade(upgradable));
+        assert!(upgrade.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+
+        // like in parking_lot, the lock is still readable for synchronous readers
+        assert!(lock.try_read().is_some());
+        drop(lock.read());
+        assert!(upgrade.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+
+        drop(reader);
+        assert!(upgrade.as_mut().poll(&mut Context::from_waker(&waker)).is_ready());
+    }
+
+    #[test]
+    fn current_thread_concurrent_future_upgrade() {
+        env_logger::try_init().ok();
+
+        let lock = Rc::new(RwLock::new(0));
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        for i in 0..100 {
+            let lock = Rc::clone(&lock);
+            runtime.spawn(async move {
+                if i % 2 == 0 {
+                    let v = lock.future_upgradable_read().await;
+                    let mut v = future_upgrade(v).await;
+                    *v += 1;
+                }
+                else {
+                    let v = lock.future_read().await;
+                    info!("read {}", *v);
+                }
+            });
+        }
+        runtime.run().unwrap();
+        assert_eq!(*lock.read(), 50);
+    }
+
+    #[test]
+    fn timed_out_waiters_leave_queue() {
+        let lock = RwLock::new(0);
+        let guard = lock.write();
+        let timer = ManualTimer::default();
+
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let (waker3, count3) = counting_waker();
+        let mut f1 = Box::pin(lock.future_write_for(Duration::from_millis(10), &timer));
+        let mut f2 = Box::pin(lock.future_upgradable_read_for(Duration::from_millis(10), &timer));
+        let mut f3 = Box::pin(lock.future_read_for(Duration::from_secs(3600), &ManualTimer::default()));
+        assert!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        assert!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+        assert!(f3.as_mut().poll(&mut Context::from_waker(&waker3)).is_pending());
+
+        timer.fire();
+        assert_eq!(f1.as_mut().poll(&mut Context::from_waker(&waker1)).map(|res| res.is_none()), Poll::Ready(true));
+        assert_eq!(f2.as_mut().poll(&mut Context::from_waker(&waker2)).map(|res| res.is_none()), Poll::Ready(true));
+
+        drop(guard);
+        assert_eq!(count1.count(), 0);
+        assert_eq!(count2.count(), 0);
+        assert_eq!(count3.count(), 1);
+        assert_eq!(f3.as_mut().poll(&mut Context::from_waker(&waker3)).map(|res| res.is_some()), Poll::Ready(true));
+    }
+
+    #[test]
+    fn current_thread_owned_guards() {
+        env_logger::try_init().ok();
+
+        let lock = Arc::new(RwLock::new(vec![0, 0]));
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        let guard = runtime.block_on(lock.future_write_owned());
+        // the guard moves into a 'static task
+        runtime.spawn(async move {
+            let mut second = OwnedRwLockWriteGuard::map(guard, |v| &mut v[1]);
+            *second += 1;
+        });
+        let inner = Arc::clone(&lock);
+        runtime.spawn(async move {
+            let v = inner.future_upgradable_read_owned().await;
+            assert_eq!(*v, vec![0, 1]);
+            drop(v);
+            let v = inner.future_read_owned().await;
+            let second = OwnedRwLockReadGuard::map(v, |v| &v[1]);
+            assert_eq!(*second, 1);
+        });
+        runtime.run().unwrap();
+        assert_eq!(Arc::strong_count(&lock), 1);
+        assert_eq!(*lock.read(), vec![0, 1]);
+    }
+
+    #[test]
+    fn poll_based_future() {
+        // a hand-written future over shared state, it can't store a FutureWrite borrowing its own lock
+        struct Increment {
+            lock: Arc<RwLock<usize>>,
+        }
+
+        impl Future for Increment {
+            type Output = usize;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<usize> {
+                self.lock.poll_write(cx).map(|mut v| {
+                    *v += 1;
+                    *v
+                })
+            }
+        }
+
+        let lock = Arc::new(RwLock::new(0));
+        let (waker, count) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+        let reader = match lock.poll_read(&mut cx) {
+            Poll::Ready(guard) => guard,
+            Poll::Pending => panic!("free lock not acquired"),
+        };
+        assert!(lock.poll_upgradable_read(&mut cx).is_ready());
+
+        let mut increment = Increment { lock: Arc::clone(&lock) };
+        assert!(Pin::new(&mut increment).poll(&mut cx).is_pending());
+        drop(reader);
+        assert_eq!(count.count(), 1);
+        assert_eq!(Pin::new(&mut increment).poll(&mut cx), Poll::Ready(1));
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn owned_readers_share_the_lock() {
+        let lock = Arc::new(RwLock::new(0));
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        runtime.block_on(async {
+            let r1 = lock.future_read_owned().await;
+            let r2 = lock.future_read_owned().await;
+            let u = lock.future_upgradable_read_owned().await;
+            assert_eq!(*r1 + *r2 + *u, 0);
+            assert!(lock.try_write().is_none());
+        });
+        assert!(lock.try_write().is_some());
+    }
 }
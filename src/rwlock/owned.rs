@@ -0,0 +1,545 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::marker::PhantomData;
+use std::future::Future;
+use std::task::{Poll, Context};
+use std::pin::Pin;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::mem;
+
+use lock_api::{RwLock, RawRwLock, RawRwLockUpgrade};
+
+use crate::wakers::{WaitKind, WaitNode};
+
+use super::FutureRawRwLock;
+use super::read::try_read_behind_upgrade;
+
+/// RwLock read guard that keeps its RwLock alive, so it can be moved around freely
+pub struct OwnedRwLockReadGuard<R, T>
+where
+    R: RawRwLock,
+{
+    lock: Arc<RwLock<FutureRawRwLock<R>, T>>,
+    data: *const T,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+unsafe impl<R, T> Send for OwnedRwLockReadGuard<R, T> where R: RawRwLock, R::GuardMarker: Send, T: Send + Sync {}
+unsafe impl<R, T> Sync for OwnedRwLockReadGuard<R, T> where R: RawRwLock, T: Send + Sync {}
+
+impl<R, T> OwnedRwLockReadGuard<R, T>
+where
+    R: RawRwLock,
+{
+    /// Returns the RwLock this guard belongs to
+    pub fn rwlock(s: &Self) -> &Arc<RwLock<FutureRawRwLock<R>, T>> {
+        &s.lock
+    }
+
+    /// Makes a new guard for a component of the locked data
+    pub fn map<U, M>(s: Self, f: M) -> OwnedMappedRwLockReadGuard<R, T, U>
+    where
+        M: FnOnce(&T) -> &U,
+    {
+        let data = f(unsafe { &*s.data }) as *const U;
+        let lock = unsafe { std::ptr::read(&s.lock) };
+        mem::forget(s);
+        OwnedMappedRwLockReadGuard {
+            lock,
+            data,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Makes a new guard for a component of the locked data, if any, or gives the original guard back
+    pub fn try_map<U, M>(s: Self, f: M) -> Result<OwnedMappedRwLockReadGuard<R, T, U>, Self>
+    where
+        M: FnOnce(&T) -> Option<&U>,
+    {
+        match f(unsafe { &*s.data }) {
+            Some(data) => {
+                let data = data as *const U;
+                Ok(OwnedRwLockReadGuard::map(s, |_| unsafe { &*data }))
+            },
+            None => Err(s),
+        }
+    }
+}
+
+impl<R, T> Deref for OwnedRwLockReadGuard<R, T>
+where
+    R: RawRwLock,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<R, T> Drop for OwnedRwLockReadGuard<R, T>
+where
+    R: RawRwLock,
+{
+    fn drop(&mut self) {
+        unsafe { self.lock.force_unlock_read(); }
+    }
+}
+
+/// Owned RwLock read guard for a component of the locked data
+pub struct OwnedMappedRwLockReadGuard<R, T, U>
+where
+    R: RawRwLock,
+{
+    lock: Arc<RwLock<FutureRawRwLock<R>, T>>,
+    data: *const U,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+unsafe impl<R, T, U> Send for OwnedMappedRwLockReadGuard<R, T, U> where R: RawRwLock, R::GuardMarker: Send, T: Send + Sync, U: Sync {}
+unsafe impl<R, T, U> Sync for OwnedMappedRwLockReadGuard<R, T, U> where R: RawRwLock, T: Send + Sync, U: Sync {}
+
+impl<R, T, U> OwnedMappedRwLockReadGuard<R, T, U>
+where
+    R: RawRwLock,
+{
+    /// Makes a new guard for a component of the locked data
+    pub fn map<V, M>(s: Self, f: M) -> OwnedMappedRwLockReadGuard<R, T, V>
+    where
+        M: FnOnce(&U) -> &V,
+    {
+        let data = f(unsafe { &*s.data }) as *const V;
+        let lock = unsafe { std::ptr::read(&s.lock) };
+        mem::forget(s);
+        OwnedMappedRwLockReadGuard {
+            lock,
+            data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<R, T, U> Deref for OwnedMappedRwLockReadGuard<R, T, U>
+where
+    R: RawRwLock,
+{
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        unsafe { &*self.data }
+    }
+}
+
+impl<R, T, U> Drop for OwnedMappedRwLockReadGuard<R, T, U>
+where
+    R: RawRwLock,
+{
+    fn drop(&mut self) {
+        unsafe { self.lock.force_unlock_read(); }
+    }
+}
+
+/// RwLock write guard that keeps its RwLock alive, so it can be moved around freely
+pub struct OwnedRwLockWriteGuard<R, T>
+where
+    R: RawRwLock,
+{
+    lock: Arc<RwLock<FutureRawRwLock<R>, T>>,
+    data: *mut T,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+unsafe impl<R, T> Send for OwnedRwLockWriteGuard<R, T> where R: RawRwLock, R::GuardMarker: Send, T: Send + Sync {}
+unsafe impl<R, T> Sync for OwnedRwLockWriteGuard<R, T> where R: RawRwLock, T: Send + Sync {}
+
+impl<R, T> OwnedRwLockWriteGuard<R, T>
+where
+    R: RawRwLock,
+{
+    /// Returns the RwLock this guard belongs to
+    pub fn rwlock(s: &Self) -> &Arc<RwLock<FutureRawRwLock<R>, T>> {
+        &s.lock
+    }
+
+    /// Makes a new guard for a component of the locked data
+    pub fn map<U, M>(s: Self, f: M) -> OwnedMappedRwLockWriteGuard<R, T, U>
+    where
+        M: FnOnce(&mut T) -> &mut U,
+    {
+        let data = f(unsafe { &mut *s.data }) as *mut U;
+        let lock = unsafe { std::ptr::read(&s.lock) };
+        mem::forget(s);
+        OwnedMappedRwLockWriteGuard {
+            lock,
+            data,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Makes a new guard for a component of the locked data, if any, or gives the original guard back
+    pub fn try_map<U, M>(s: Self, f: M) -> Result<OwnedMappedRwLockWriteGuard<R, T, U>, Self>
+    where
+        M: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        match f(unsafe { &mut *s.data }) {
+            Some(data) => {
+                let data = data as *mut U;
+                Ok(OwnedRwLockWriteGuard::map(s, |_| unsafe { &mut *data }))
+            },
+            None => Err(s),
+        }
+    }
+}
+
+impl<R, T> Deref for OwnedRwLockWriteGuard<R, T>
+where
+    R: RawRwLock,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<R, T> DerefMut for OwnedRwLockWriteGuard<R, T>
+where
+    R: RawRwLock,
+{
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<R, T> Drop for OwnedRwLockWriteGuard<R, T>
+where
+    R: RawRwLock,
+{
+    fn drop(&mut self) {
+        unsafe { self.lock.force_unlock_write(); }
+    }
+}
+
+/// Owned RwLock write guard for a component of the locked data
+pub struct OwnedMappedRwLockWriteGuard<R, T, U>
+where
+    R: RawRwLock,
+{
+    lock: Arc<RwLock<FutureRawRwLock<R>, T>>,
+    data: *mut U,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+unsafe impl<R, T, U> Send for OwnedMappedRwLockWriteGuard<R, T, U> where R: RawRwLock, R::GuardMarker: Send, T: Send + Sync, U: Send {}
+unsafe impl<R, T, U> Sync for OwnedMappedRwLockWriteGuard<R, T, U> where R: RawRwLock, T: Send + Sync, U: Sync {}
+
+impl<R, T, U> OwnedMappedRwLockWriteGuard<R, T, U>
+where
+    R: RawRwLock,
+{
+    /// Makes a new guard for a component of the locked data
+    pub fn map<V, M>(s: Self, f: M) -> OwnedMappedRwLockWriteGuard<R, T, V>
+    where
+        M: FnOnce(&mut U) -> &mut V,
+    {
+        let data = f(unsafe { &mut *s.data }) as *mut V;
+        let lock = unsafe { std::ptr::read(&s.lock) };
+        mem::forget(s);
+        OwnedMappedRwLockWriteGuard {
+            lock,
+            data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<R, T, U> Deref for OwnedMappedRwLockWriteGuard<R, T, U>
+where
+    R: RawRwLock,
+{
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        unsafe { &*self.data }
+    }
+}
+
+impl<R, T, U> DerefMut for OwnedMappedRwLockWriteGuard<R, T, U>
+where
+    R: RawRwLock,
+{
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<R, T, U> Drop for OwnedMappedRwLockWriteGuard<R, T, U>
+where
+    R: RawRwLock,
+{
+    fn drop(&mut self) {
+        unsafe { self.lock.force_unlock_write(); }
+    }
+}
+
+/// RwLock upgradable-read guard that keeps its RwLock alive, so it can be moved around freely
+pub struct OwnedRwLockUpgradableReadGuard<R, T>
+where
+    R: RawRwLockUpgrade,
+{
+    lock: Arc<RwLock<FutureRawRwLock<R>, T>>,
+    data: *const T,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+unsafe impl<R, T> Send for OwnedRwLockUpgradableReadGuard<R, T> where R: RawRwLockUpgrade, R::GuardMarker: Send, T: Send + Sync {}
+unsafe impl<R, T> Sync for OwnedRwLockUpgradableReadGuard<R, T> where R: RawRwLockUpgrade, T: Send + Sync {}
+
+impl<R, T> OwnedRwLockUpgradableReadGuard<R, T>
+where
+    R: RawRwLockUpgrade,
+{
+    /// Returns the RwLock this guard belongs to
+    pub fn rwlock(s: &Self) -> &Arc<RwLock<FutureRawRwLock<R>, T>> {
+        &s.lock
+    }
+}
+
+impl<R, T> Deref for OwnedRwLockUpgradableReadGuard<R, T>
+where
+    R: RawRwLockUpgrade,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<R, T> Drop for OwnedRwLockUpgradableReadGuard<R, T>
+where
+    R: RawRwLockUpgrade,
+{
+    fn drop(&mut self) {
+        unsafe { self.lock.raw().unlock_upgradable(); }
+    }
+}
+
+/// Wrapper to read from an Arc'd RwLock in Future-style, resolving to an owned guard
+pub struct FutureReadOwned<R, T>
+where
+    R: RawRwLock,
+{
+    lock: Arc<RwLock<FutureRawRwLock<R>, T>>,
+    waiter: WaitNode,
+}
+
+impl<R, T> FutureReadOwned<R, T>
+where
+    R: RawRwLock,
+{
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn new(lock: Arc<RwLock<FutureRawRwLock<R>, T>>) -> Self {
+        FutureReadOwned {
+            lock,
+            waiter: WaitNode::located(WaitKind::Shared),
+        }
+    }
+}
+
+impl<R, T> Future for FutureReadOwned<R, T>
+where
+    R: RawRwLock,
+{
+    type Output = OwnedRwLockReadGuard<R, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let lock = &self.lock;
+        let res = unsafe {
+            lock.raw().waiters.poll_acquire(&self.waiter, cx, || {
+                try_read_behind_upgrade(lock).map(|guard| {
+                    // the owned guard takes over the unlocking
+                    let data = &*guard as *const T;
+                    mem::forget(guard);
+                    data
+                })
+            })
+        };
+        res.map(|data| OwnedRwLockReadGuard {
+            lock: Arc::clone(lock),
+            data,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<R, T> Drop for FutureReadOwned<R, T>
+where
+    R: RawRwLock,
+{
+    fn drop(&mut self) {
+        // a cancelled future must not swallow the wakeup meant for the next waiter
+        unsafe { self.lock.raw().waiters.cancel(&self.waiter); }
+    }
+}
+
+/// Trait to permit FutureReadOwned implementation on Arc'd RwLock
+pub trait FutureReadableOwned<R: RawRwLock, T> {
+    /// Returns the read-lock without blocking, as a guard that keeps the RwLock alive
+    fn future_read_owned(&self) -> FutureReadOwned<R, T>;
+}
+
+impl<R: RawRwLock, T> FutureReadableOwned<R, T> for Arc<RwLock<FutureRawRwLock<R>, T>> {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_read_owned(&self) -> FutureReadOwned<R, T> {
+        FutureReadOwned::new(Arc::clone(self))
+    }
+}
+
+/// Wrapper to write to an Arc'd RwLock in Future-style, resolving to an owned guard
+pub struct FutureWriteOwned<R, T>
+where
+    R: RawRwLock,
+{
+    lock: Arc<RwLock<FutureRawRwLock<R>, T>>,
+    waiter: WaitNode,
+}
+
+impl<R, T> FutureWriteOwned<R, T>
+where
+    R: RawRwLock,
+{
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn new(lock: Arc<RwLock<FutureRawRwLock<R>, T>>) -> Self {
+        FutureWriteOwned {
+            lock,
+            waiter: WaitNode::located(WaitKind::Exclusive),
+        }
+    }
+}
+
+impl<R, T> Future for FutureWriteOwned<R, T>
+where
+    R: RawRwLock,
+{
+    type Output = OwnedRwLockWriteGuard<R, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let lock = &self.lock;
+        let res = unsafe {
+            lock.raw().waiters.poll_acquire(&self.waiter, cx, || {
+                lock.try_write().map(|mut guard| {
+                    // the owned guard takes over the unlocking
+                    let data = &mut *guard as *mut T;
+                    mem::forget(guard);
+                    data
+                })
+            })
+        };
+        res.map(|data| OwnedRwLockWriteGuard {
+            lock: Arc::clone(lock),
+            data,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<R, T> Drop for FutureWriteOwned<R, T>
+where
+    R: RawRwLock,
+{
+    fn drop(&mut self) {
+        // a cancelled future must not swallow the wakeup meant for the next waiter
+        unsafe { self.lock.raw().waiters.cancel(&self.waiter); }
+    }
+}
+
+/// Trait to permit FutureWriteOwned implementation on Arc'd RwLock
+pub trait FutureWriteableOwned<R: RawRwLock, T> {
+    /// Returns the write-lock without blocking, as a guard that keeps the RwLock alive
+    fn future_write_owned(&self) -> FutureWriteOwned<R, T>;
+}
+
+impl<R: RawRwLock, T> FutureWriteableOwned<R, T> for Arc<RwLock<FutureRawRwLock<R>, T>> {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_write_owned(&self) -> FutureWriteOwned<R, T> {
+        FutureWriteOwned::new(Arc::clone(self))
+    }
+}
+
+/// Wrapper to upgradable-read from an Arc'd RwLock in Future-style, resolving to an owned guard
+pub struct FutureUpgradableReadOwned<R, T>
+where
+    R: RawRwLockUpgrade,
+{
+    lock: Arc<RwLock<FutureRawRwLock<R>, T>>,
+    waiter: WaitNode,
+}
+
+impl<R, T> FutureUpgradableReadOwned<R, T>
+where
+    R: RawRwLockUpgrade,
+{
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn new(lock: Arc<RwLock<FutureRawRwLock<R>, T>>) -> Self {
+        FutureUpgradableReadOwned {
+            lock,
+            waiter: WaitNode::located(WaitKind::Upgradable),
+        }
+    }
+}
+
+impl<R, T> Future for FutureUpgradableReadOwned<R, T>
+where
+    R: RawRwLockUpgrade,
+{
+    type Output = OwnedRwLockUpgradableReadGuard<R, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let lock = &self.lock;
+        let res = unsafe {
+            lock.raw().waiters.poll_acquire(&self.waiter, cx, || {
+                lock.try_upgradable_read().map(|guard| {
+                    // the owned guard takes over the unlocking
+                    let data = &*guard as *const T;
+                    mem::forget(guard);
+                    data
+                })
+            })
+        };
+        res.map(|data| OwnedRwLockUpgradableReadGuard {
+            lock: Arc::clone(lock),
+            data,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<R, T> Drop for FutureUpgradableReadOwned<R, T>
+where
+    R: RawRwLockUpgrade,
+{
+    fn drop(&mut self) {
+        // a cancelled future must not swallow the wakeup meant for the next waiter
+        unsafe { self.lock.raw().waiters.cancel(&self.waiter); }
+    }
+}
+
+/// Trait to permit FutureUpgradableReadOwned implementation on Arc'd RwLock
+pub trait FutureUpgradableReadableOwned<R: RawRwLockUpgrade, T> {
+    /// Returns the upgradable-read-lock without blocking, as a guard that keeps the RwLock alive
+    fn future_upgradable_read_owned(&self) -> FutureUpgradableReadOwned<R, T>;
+}
+
+impl<R: RawRwLockUpgrade, T> FutureUpgradableReadableOwned<R, T> for Arc<RwLock<FutureRawRwLock<R>, T>> {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_upgradable_read_owned(&self) -> FutureUpgradableReadOwned<R, T> {
+        FutureUpgradableReadOwned::new(Arc::clone(self))
+    }
+}
@@ -9,11 +9,25 @@ use std::marker::PhantomData;
 use std::future::Future;
 use std::task::{Poll, Context};
 use std::pin::Pin;
+use std::time::{Duration, Instant};
 
 use lock_api::{RwLock, RawRwLock, RwLockReadGuard};
 
+use crate::timeout::{FutureTimeout, Timer};
+use crate::wakers::{WaitKind, WaitNode};
+
 use super::FutureRawRwLock;
 
+/// Like `try_read`, but fails while a FutureUpgrade is pending
+pub(crate) fn try_read_behind_upgrade<R: RawRwLock, T>(lock: &RwLock<FutureRawRwLock<R>, T>) -> Option<RwLockReadGuard<'_, FutureRawRwLock<R>, T>> {
+    if unsafe { lock.raw() }.upgrade_pending() {
+        None
+    }
+    else {
+        lock.try_read()
+    }
+}
+
 /// Wrapper to read from RwLock in Future-style
 pub struct FutureRead<'a, R, T>
 where
@@ -21,6 +35,7 @@ where
     T: 'a,
 {
     lock: &'a RwLock<FutureRawRwLock<R>, T>,
+    waiter: WaitNode,
     _contents: PhantomData<T>,
     _locktype: PhantomData<R>,
 }
@@ -30,9 +45,11 @@ where
     R: RawRwLock + 'a,
     T: 'a,
 {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
     fn new(lock: &'a RwLock<FutureRawRwLock<R>, T>) -> Self {
         FutureRead {
             lock,
+            waiter: WaitNode::located(WaitKind::Shared),
             _locktype: PhantomData,
             _contents: PhantomData,
         }
@@ -47,29 +64,53 @@ where
     type Output = RwLockReadGuard<'a, FutureRawRwLock<R>, T>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        unsafe { self.lock.raw().atomic_lock(); }
-        match self.lock.try_read() {
-            Some(read_lock) => {
-                unsafe { self.lock.raw().atomic_unlock(); }
-                Poll::Ready(read_lock)
-            },
-            None => {
-                // Register Waker so we can notified when we can be polled again
-                unsafe { self.lock.raw().register_waker(cx.waker()); }
-                Poll::Pending
-            },
-        }
+        let lock = self.lock;
+        unsafe { lock.raw().waiters.poll_acquire(&self.waiter, cx, || try_read_behind_upgrade(lock)) }
+    }
+}
+
+impl<'a, R, T> Drop for FutureRead<'a, R, T>
+where
+    R: RawRwLock + 'a,
+    T: 'a,
+{
+    fn drop(&mut self) {
+        // a cancelled future must not swallow the wakeup meant for the next waiter
+        // (RwLocks never hand the lock over, so there's nothing else to release)
+        unsafe { self.lock.raw().waiters.cancel(&self.waiter); }
     }
 }
 
 /// Trait to permit FutureRead implementation on wrapped RwLock (not RwLock itself)
 pub trait FutureReadable<R: RawRwLock, T> {
     /// Returns the read-lock without blocking
-    fn future_read(&self) -> FutureRead<R, T>;
+    fn future_read(&self) -> FutureRead<'_, R, T>;
+
+    /// Tries to take the read-lock, registering the task to be woken at the next release if it fails
+    ///
+    /// Meant for hand-written `poll` functions that can't store a FutureRead, the task doesn't keep a place in line.
+    fn poll_read(&self, cx: &mut Context) -> Poll<RwLockReadGuard<'_, FutureRawRwLock<R>, T>>;
+
+    /// Returns the read-lock without blocking, giving up after the given timeout
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_read_for<Tm: Timer>(&self, timeout: Duration, timer: &Tm) -> FutureTimeout<FutureRead<'_, R, T>, Tm::Delay> {
+        FutureTimeout::after(self.future_read(), timeout, timer)
+    }
+
+    /// Returns the read-lock without blocking, giving up at the given deadline
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_read_until<Tm: Timer>(&self, deadline: Instant, timer: &Tm) -> FutureTimeout<FutureRead<'_, R, T>, Tm::Delay> {
+        FutureTimeout::new(self.future_read(), timer.delay_until(deadline))
+    }
 }
 
 impl<R: RawRwLock, T> FutureReadable<R, T> for RwLock<FutureRawRwLock<R>, T> {
-    fn future_read(&self) -> FutureRead<R, T> {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_read(&self) -> FutureRead<'_, R, T> {
         FutureRead::new(self)
     }
+
+    fn poll_read(&self, cx: &mut Context) -> Poll<RwLockReadGuard<'_, FutureRawRwLock<R>, T>> {
+        unsafe { self.raw().waiters.poll_acquire_unpinned(cx, || try_read_behind_upgrade(self)) }
+    }
 }
@@ -9,40 +9,76 @@ use std::marker::PhantomData;
 use std::future::Future;
 use std::task::{Poll, Context};
 use std::pin::Pin;
+use std::time::{Duration, Instant};
 
-use lock_api::{RwLock, RawRwLockUpgrade, RwLockUpgradableReadGuard};
+use lock_api::{RwLock, RawRwLockUpgrade, RawRwLockUpgradeDowngrade, RwLockUpgradableReadGuard};
+
+use crate::timeout::{FutureTimeout, Timer};
+use crate::wakers::{WaitKind, WaitNode};
 
 use super::FutureRawRwLock;
 
 unsafe impl<R> RawRwLockUpgrade for FutureRawRwLock<R> where R: RawRwLockUpgrade {
     fn lock_upgradable(&self) {
-        self.create_wakers_list();
-
         self.inner.lock_upgradable();
+        #[cfg(feature = "stats")]
+        self.stats.shared_acquired();
+        self.waiters.locked();
     }
 
     fn try_lock_upgradable(&self) -> bool {
-        self.create_wakers_list();
-
-        self.inner.try_lock_upgradable()
+        let locked = self.inner.try_lock_upgradable();
+        if locked {
+            #[cfg(feature = "stats")]
+            self.stats.shared_acquired();
+            self.waiters.locked();
+        }
+        locked
     }
 
     fn unlock_upgradable(&self)  {
+        #[cfg(feature = "stats")]
+        self.stats.shared_released();
+        self.waiters.released(WaitKind::Upgradable);
         self.inner.unlock_upgradable();
 
-        self.wake_up();
+        self.waiters.wake_up();
     }
 
     fn upgrade(&self) {
-        self.create_wakers_list();
-
         self.inner.upgrade();
+        #[cfg(feature = "stats")]
+        self.stats.upgraded();
     }
 
     fn try_upgrade(&self) -> bool {
-        self.create_wakers_list();
+        let upgraded = self.inner.try_upgrade();
+        #[cfg(feature = "stats")]
+        {
+            if upgraded {
+                self.stats.upgraded();
+            }
+        }
+        upgraded
+    }
+}
+
+unsafe impl<R> RawRwLockUpgradeDowngrade for FutureRawRwLock<R> where R: RawRwLockUpgradeDowngrade {
+    fn downgrade_upgradable(&self) {
+        // no stats to update, an upgradable reader is already counted among the readers, and stays there
+        self.inner.downgrade_upgradable();
 
-        self.inner.try_upgrade()
+        // another upgradable reader can get in
+        self.waiters.wake_readers(true);
+    }
+
+    fn downgrade_to_upgradable(&self) {
+        #[cfg(feature = "stats")]
+        self.stats.downgraded();
+        self.inner.downgrade_to_upgradable();
+
+        // we're still the upgradable reader, only plain readers can get in
+        self.waiters.wake_readers(false);
     }
 }
 
@@ -53,6 +89,7 @@ where
     T: 'a,
 {
     lock: &'a RwLock<FutureRawRwLock<R>, T>,
+    waiter: WaitNode,
     _contents: PhantomData<T>,
     _locktype: PhantomData<R>,
 }
@@ -62,9 +99,11 @@ where
     R: RawRwLockUpgrade + 'a,
     T: 'a,
 {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
     fn new(lock: &'a RwLock<FutureRawRwLock<R>, T>) -> Self {
         FutureUpgradableRead {
             lock,
+            waiter: WaitNode::located(WaitKind::Upgradable),
             _contents: PhantomData,
             _locktype: PhantomData,
         }
@@ -79,29 +118,53 @@ where
     type Output = RwLockUpgradableReadGuard<'a, FutureRawRwLock<R>, T>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        unsafe { self.lock.raw().atomic_lock(); }
-        match self.lock.try_upgradable_read() {
-            Some(upgradable_lock) => {
-                unsafe { self.lock.raw().atomic_unlock(); }
-                Poll::Ready(upgradable_lock)
-            },
-            None => {
-                // Register Waker so we can notified when we can be polled again
-                unsafe { self.lock.raw().register_waker(cx.waker()); }
-                Poll::Pending
-            },
-        }
+        let lock = self.lock;
+        unsafe { lock.raw().waiters.poll_acquire(&self.waiter, cx, || lock.try_upgradable_read()) }
+    }
+}
+
+impl<'a, R, T> Drop for FutureUpgradableRead<'a, R, T>
+where
+    R: RawRwLockUpgrade + 'a,
+    T: 'a,
+{
+    fn drop(&mut self) {
+        // a cancelled future must not swallow the wakeup meant for the next waiter
+        // (RwLocks never hand the lock over, so there's nothing else to release)
+        unsafe { self.lock.raw().waiters.cancel(&self.waiter); }
     }
 }
 
 /// Trait to permit FutureUpgradableRead implementation on wrapped RwLock (not RwLock itself)
 pub trait FutureUpgradableReadable<R: RawRwLockUpgrade, T> {
     /// Returns the upgradable-read-lock without blocking
-    fn future_upgradable_read(&self) -> FutureUpgradableRead<R, T>;
+    fn future_upgradable_read(&self) -> FutureUpgradableRead<'_, R, T>;
+
+    /// Tries to take the upgradable-read-lock, registering the task to be woken at the next release if it fails
+    ///
+    /// Meant for hand-written `poll` functions that can't store a FutureUpgradableRead, the task doesn't keep a place in line.
+    fn poll_upgradable_read(&self, cx: &mut Context) -> Poll<RwLockUpgradableReadGuard<'_, FutureRawRwLock<R>, T>>;
+
+    /// Returns the upgradable-read-lock without blocking, giving up after the given timeout
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_upgradable_read_for<Tm: Timer>(&self, timeout: Duration, timer: &Tm) -> FutureTimeout<FutureUpgradableRead<'_, R, T>, Tm::Delay> {
+        FutureTimeout::after(self.future_upgradable_read(), timeout, timer)
+    }
+
+    /// Returns the upgradable-read-lock without blocking, giving up at the given deadline
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_upgradable_read_until<Tm: Timer>(&self, deadline: Instant, timer: &Tm) -> FutureTimeout<FutureUpgradableRead<'_, R, T>, Tm::Delay> {
+        FutureTimeout::new(self.future_upgradable_read(), timer.delay_until(deadline))
+    }
 }
 
 impl<R: RawRwLockUpgrade, T> FutureUpgradableReadable<R, T> for RwLock<FutureRawRwLock<R>, T> {
-    fn future_upgradable_read(&self) -> FutureUpgradableRead<R, T> {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_upgradable_read(&self) -> FutureUpgradableRead<'_, R, T> {
         FutureUpgradableRead::new(self)
     }
+
+    fn poll_upgradable_read(&self, cx: &mut Context) -> Poll<RwLockUpgradableReadGuard<'_, FutureRawRwLock<R>, T>> {
+        unsafe { self.raw().waiters.poll_acquire_unpinned(cx, || self.try_upgradable_read()) }
+    }
 }
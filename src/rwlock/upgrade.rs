@@ -0,0 +1,108 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::future::Future;
+use std::task::{Poll, Context};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+
+use lock_api::{RawRwLockUpgrade, RwLockUpgradableReadGuard, RwLockWriteGuard};
+
+use crate::wakers::{WaitKind, WaitNode};
+
+use super::FutureRawRwLock;
+
+/// Wrapper to upgrade an upgradable-read guard in Future-style
+pub struct FutureUpgrade<'a, R, T>
+where
+    R: RawRwLockUpgrade + 'a,
+    T: 'a,
+{
+    guard: Option<RwLockUpgradableReadGuard<'a, FutureRawRwLock<R>, T>>,
+    waiter: WaitNode,
+    // whether this future is keeping new readers out
+    upgrading: bool,
+}
+
+impl<'a, R, T> FutureUpgrade<'a, R, T>
+where
+    R: RawRwLockUpgrade + 'a,
+    T: 'a,
+{
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn new(guard: RwLockUpgradableReadGuard<'a, FutureRawRwLock<R>, T>) -> Self {
+        FutureUpgrade {
+            guard: Some(guard),
+            waiter: WaitNode::located(WaitKind::Upgrade),
+            upgrading: false,
+        }
+    }
+}
+
+impl<'a, R, T> Future for FutureUpgrade<'a, R, T>
+where
+    R: RawRwLockUpgrade + 'a,
+    T: 'a,
+{
+    type Output = RwLockWriteGuard<'a, FutureRawRwLock<R>, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // nothing is moved out of self but the guard, which isn't pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let lock = RwLockUpgradableReadGuard::rwlock(this.guard.as_ref().expect("FutureUpgrade polled after completion"));
+        let raw = unsafe { lock.raw() };
+        let guard = &mut this.guard;
+        let res = unsafe {
+            raw.waiters.poll_acquire(&this.waiter, cx, || {
+                match RwLockUpgradableReadGuard::try_upgrade(guard.take()?) {
+                    Ok(write_lock) => Some(write_lock),
+                    Err(upgradable_lock) => {
+                        *guard = Some(upgradable_lock);
+                        None
+                    },
+                }
+            })
+        };
+        // new readers back off until the upgrade is done, otherwise they could keep it waiting forever
+        if res.is_pending() != this.upgrading {
+            this.upgrading = res.is_pending();
+            raw.upgrading.store(this.upgrading, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+impl<'a, R, T> Drop for FutureUpgrade<'a, R, T>
+where
+    R: RawRwLockUpgrade + 'a,
+    T: 'a,
+{
+    fn drop(&mut self) {
+        // the guard is released right after, waking whoever is next
+        if let Some(ref guard) = self.guard {
+            let raw = unsafe { RwLockUpgradableReadGuard::rwlock(guard).raw() };
+            if self.upgrading {
+                raw.upgrading.store(false, Ordering::Relaxed);
+            }
+            unsafe { raw.waiters.cancel(&self.waiter); }
+        }
+    }
+}
+
+/// Upgrades an upgradable-read guard to a write guard without blocking,
+/// waiting for the other readers to go away
+///
+/// New future readers wait behind the upgrade, so it completes even if readers keep arriving.
+/// Synchronous `read` and `try_read` keep parking_lot's behaviour and can still get in.
+#[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+pub fn future_upgrade<'a, R, T>(guard: RwLockUpgradableReadGuard<'a, FutureRawRwLock<R>, T>) -> FutureUpgrade<'a, R, T>
+where
+    R: RawRwLockUpgrade + 'a,
+    T: 'a,
+{
+    FutureUpgrade::new(guard)
+}
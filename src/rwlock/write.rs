@@ -9,9 +9,13 @@ use std::marker::PhantomData;
 use std::future::Future;
 use std::task::{Poll, Context};
 use std::pin::Pin;
+use std::time::{Duration, Instant};
 
 use lock_api::{RwLock, RawRwLock, RwLockWriteGuard};
 
+use crate::timeout::{FutureTimeout, Timer};
+use crate::wakers::{WaitKind, WaitNode};
+
 use super::FutureRawRwLock;
 
 /// Wrapper to write into RwLock in Future-style
@@ -21,6 +25,7 @@ where
     T: 'a,
 {
     lock: &'a RwLock<FutureRawRwLock<R>, T>,
+    waiter: WaitNode,
     _contents: PhantomData<T>,
     _locktype: PhantomData<R>,
 }
@@ -30,9 +35,11 @@ where
     R: RawRwLock + 'a,
     T: 'a,
 {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
     fn new(lock: &'a RwLock<FutureRawRwLock<R>, T>) -> Self {
         FutureWrite {
             lock,
+            waiter: WaitNode::located(WaitKind::Exclusive),
             _contents: PhantomData,
             _locktype: PhantomData,
         }
@@ -47,29 +54,53 @@ where
     type Output = RwLockWriteGuard<'a, FutureRawRwLock<R>, T>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        unsafe { self.lock.raw().atomic_lock(); }
-        match self.lock.try_write() {
-            Some(write_lock) => {
-                unsafe { self.lock.raw().atomic_unlock(); }
-                Poll::Ready(write_lock)
-            },
-            None => {
-                // Register Waker so we can notified when we can be polled again
-                unsafe { self.lock.raw().register_waker(cx.waker()); }
-                Poll::Pending
-            },
-        }
+        let lock = self.lock;
+        unsafe { lock.raw().waiters.poll_acquire(&self.waiter, cx, || lock.try_write()) }
+    }
+}
+
+impl<'a, R, T> Drop for FutureWrite<'a, R, T>
+where
+    R: RawRwLock + 'a,
+    T: 'a,
+{
+    fn drop(&mut self) {
+        // a cancelled future must not swallow the wakeup meant for the next waiter
+        // (RwLocks never hand the lock over, so there's nothing else to release)
+        unsafe { self.lock.raw().waiters.cancel(&self.waiter); }
     }
 }
 
 /// Trait to permit FutureWrite implementation on wrapped RwLock (not RwLock itself)
 pub trait FutureWriteable<R: RawRwLock, T> {
     /// Returns the write-lock without blocking
-    fn future_write(&self) -> FutureWrite<R, T>;
+    fn future_write(&self) -> FutureWrite<'_, R, T>;
+
+    /// Tries to take the write-lock, registering the task to be woken at the next release if it fails
+    ///
+    /// Meant for hand-written `poll` functions that can't store a FutureWrite, the task doesn't keep a place in line.
+    fn poll_write(&self, cx: &mut Context) -> Poll<RwLockWriteGuard<'_, FutureRawRwLock<R>, T>>;
+
+    /// Returns the write-lock without blocking, giving up after the given timeout
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_write_for<Tm: Timer>(&self, timeout: Duration, timer: &Tm) -> FutureTimeout<FutureWrite<'_, R, T>, Tm::Delay> {
+        FutureTimeout::after(self.future_write(), timeout, timer)
+    }
+
+    /// Returns the write-lock without blocking, giving up at the given deadline
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_write_until<Tm: Timer>(&self, deadline: Instant, timer: &Tm) -> FutureTimeout<FutureWrite<'_, R, T>, Tm::Delay> {
+        FutureTimeout::new(self.future_write(), timer.delay_until(deadline))
+    }
 }
 
 impl<R: RawRwLock, T> FutureWriteable<R, T> for RwLock<FutureRawRwLock<R>, T> {
-    fn future_write(&self) -> FutureWrite<R, T> {
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    fn future_write(&self) -> FutureWrite<'_, R, T> {
         FutureWrite::new(self)
     }
+
+    fn poll_write(&self, cx: &mut Context) -> Poll<RwLockWriteGuard<'_, FutureRawRwLock<R>, T>> {
+        unsafe { self.raw().waiters.poll_acquire_unpinned(cx, || self.try_write()) }
+    }
 }
@@ -0,0 +1,515 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Poll, Context};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use crate::timeout::{FutureTimeout, Timer};
+use crate::wakers::{WaiterQueue, WaitKind, WaitNode};
+
+/// Error returned by `acquire` when the Semaphore has been closed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AcquireError;
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("semaphore closed")
+    }
+}
+
+impl Error for AcquireError {}
+
+/// Error returned by `try_acquire`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryAcquireError {
+    /// The Semaphore has been closed
+    Closed,
+    /// There aren't enough permits, or somebody else is waiting for them
+    NoPermits,
+}
+
+impl fmt::Display for TryAcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryAcquireError::Closed => f.write_str("semaphore closed"),
+            TryAcquireError::NoPermits => f.write_str("no permits available"),
+        }
+    }
+}
+
+impl Error for TryAcquireError {}
+
+/// A Future-compatible counting Semaphore
+///
+/// Permits are handed out in FIFO order: once a future is waiting, nobody can overtake it,
+/// so a large request isn't starved by a stream of small ones.
+pub struct Semaphore {
+    waiters: WaiterQueue,
+    permits: AtomicUsize,
+    closed: AtomicBool,
+}
+
+impl Semaphore {
+    /// Creates a new Semaphore with the given number of permits
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            waiters: WaiterQueue::new(),
+            permits: AtomicUsize::new(permits),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the number of permits that can be acquired right now
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Acquire)
+    }
+
+    /// Adds permits to the Semaphore, handing them over to the waiting futures
+    pub fn add_permits(&self, permits: usize) {
+        let mut current = self.permits.load(Ordering::Relaxed);
+        loop {
+            let new = current.checked_add(permits).expect("Semaphore permits overflow");
+            match self.permits.compare_exchange_weak(current, new, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(x) => current = x,
+            }
+        }
+        if self.is_closed() {
+            // the waiters are being told to give up
+            return;
+        }
+        self.waiters.grant(|kind| match kind {
+            WaitKind::Permits(permits) => self.take(permits),
+            _ => false,
+        });
+    }
+
+    /// Closes the Semaphore, waiting futures and further attempts to acquire permits fail
+    ///
+    /// Permits already acquired are unaffected.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.waiters.notify_all();
+    }
+
+    /// Returns true if the Semaphore has been closed
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn take(&self, permits: usize) -> bool {
+        let mut current = self.permits.load(Ordering::Relaxed);
+        loop {
+            if current < permits {
+                return false;
+            }
+            match self.permits.compare_exchange_weak(current, current - permits, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(x) => current = x,
+            }
+        }
+    }
+
+    fn try_take(&self, permits: usize) -> Result<(), TryAcquireError> {
+        if self.is_closed() {
+            Err(TryAcquireError::Closed)
+        }
+        else if self.waiters.is_empty() && self.take(permits) {
+            Ok(())
+        }
+        else {
+            Err(TryAcquireError::NoPermits)
+        }
+    }
+
+    /// Shared by the borrowing and the owned acquire futures
+    ///
+    /// # Safety
+    /// same as WaiterQueue::poll_grant
+    unsafe fn poll_take(&self, node: &WaitNode, permits: usize, cx: &mut Context) -> Poll<Result<(), AcquireError>> {
+        match self.waiters.poll_grant(node, cx, || !self.is_closed() && self.take(permits)) {
+            Poll::Ready(true) => Poll::Ready(Ok(())),
+            // only close wakes without granting
+            Poll::Ready(false) => Poll::Ready(Err(AcquireError)),
+            Poll::Pending => {
+                // close could have missed us while we were registering, pairs with the fence in notify_all
+                if self.is_closed() {
+                    self.cancel(node, permits);
+                    Poll::Ready(Err(AcquireError))
+                }
+                else {
+                    Poll::Pending
+                }
+            },
+        }
+    }
+
+    /// # Safety
+    /// same as WaiterQueue::cancel
+    unsafe fn cancel(&self, node: &WaitNode, permits: usize) {
+        if self.waiters.cancel(node) {
+            // permits were granted to us, give them back
+            self.add_permits(permits);
+        }
+        else {
+            // we could have been the head of the line, holding back smaller requests
+            self.add_permits(0);
+        }
+    }
+
+    /// Attempts to acquire the given number of permits without waiting
+    pub fn try_acquire(&self, permits: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_take(permits).map(|()| SemaphorePermit {
+            semaphore: self,
+            permits,
+        })
+    }
+
+    /// Acquires the given number of permits without blocking
+    pub fn acquire(&self, permits: usize) -> FutureAcquire<'_> {
+        FutureAcquire {
+            semaphore: self,
+            permits,
+            waiter: WaitNode::new(WaitKind::Permits(permits)),
+            done: false,
+        }
+    }
+
+    /// Acquires the given number of permits without blocking, giving up after the given timeout
+    pub fn acquire_for<Tm: Timer>(&self, permits: usize, timeout: Duration, timer: &Tm) -> FutureTimeout<FutureAcquire<'_>, Tm::Delay> {
+        FutureTimeout::after(self.acquire(permits), timeout, timer)
+    }
+
+    /// Acquires the given number of permits without blocking, giving up at the given deadline
+    pub fn acquire_until<Tm: Timer>(&self, permits: usize, deadline: Instant, timer: &Tm) -> FutureTimeout<FutureAcquire<'_>, Tm::Delay> {
+        FutureTimeout::new(self.acquire(permits), timer.delay_until(deadline))
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("permits", &self.available_permits())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+/// Permits acquired from a Semaphore, given back when dropped
+#[must_use = "if unused the permits are immediately given back"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    /// Returns the number of permits held
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Drops the permits without giving them back to the Semaphore
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+/// Wrapper to acquire Semaphore permits in Future-style
+pub struct FutureAcquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    waiter: WaitNode,
+    done: bool,
+}
+
+impl<'a> Future for FutureAcquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // only waiter is structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let res = unsafe { this.semaphore.poll_take(&this.waiter, this.permits, cx) };
+        if res.is_ready() {
+            this.done = true;
+        }
+        let (semaphore, permits) = (this.semaphore, this.permits);
+        res.map(|res| res.map(|()| SemaphorePermit { semaphore, permits }))
+    }
+}
+
+impl<'a> Drop for FutureAcquire<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            // a cancelled future must not keep permits or hold back the other waiters
+            unsafe { self.semaphore.cancel(&self.waiter, self.permits); }
+        }
+    }
+}
+
+/// Permits acquired from an Arc'd Semaphore, given back when dropped
+#[must_use = "if unused the permits are immediately given back"]
+pub struct OwnedSemaphorePermit {
+    semaphore: Arc<Semaphore>,
+    permits: usize,
+}
+
+impl OwnedSemaphorePermit {
+    /// Returns the number of permits held
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Returns the Semaphore the permits come from
+    pub fn semaphore(&self) -> &Arc<Semaphore> {
+        &self.semaphore
+    }
+
+    /// Drops the permits without giving them back to the Semaphore
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for OwnedSemaphorePermit {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+/// Wrapper to acquire permits of an Arc'd Semaphore in Future-style, resolving to owned permits
+pub struct FutureAcquireOwned {
+    semaphore: Arc<Semaphore>,
+    permits: usize,
+    waiter: WaitNode,
+    done: bool,
+}
+
+impl Future for FutureAcquireOwned {
+    type Output = Result<OwnedSemaphorePermit, AcquireError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // only waiter is structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let res = unsafe { this.semaphore.poll_take(&this.waiter, this.permits, cx) };
+        if res.is_ready() {
+            this.done = true;
+        }
+        let (semaphore, permits) = (&this.semaphore, this.permits);
+        res.map(|res| res.map(|()| OwnedSemaphorePermit {
+            semaphore: Arc::clone(semaphore),
+            permits,
+        }))
+    }
+}
+
+impl Drop for FutureAcquireOwned {
+    fn drop(&mut self) {
+        if !self.done {
+            // a cancelled future must not keep permits or hold back the other waiters
+            unsafe { self.semaphore.cancel(&self.waiter, self.permits); }
+        }
+    }
+}
+
+/// Trait to permit FutureAcquireOwned implementation on Arc'd Semaphore
+pub trait FutureAcquirableOwned {
+    /// Attempts to acquire the given number of permits without waiting, as permits that keep the Semaphore alive
+    fn try_acquire_owned(&self, permits: usize) -> Result<OwnedSemaphorePermit, TryAcquireError>;
+
+    /// Acquires the given number of permits without blocking, as permits that keep the Semaphore alive
+    fn acquire_owned(&self, permits: usize) -> FutureAcquireOwned;
+}
+
+impl FutureAcquirableOwned for Arc<Semaphore> {
+    fn try_acquire_owned(&self, permits: usize) -> Result<OwnedSemaphorePermit, TryAcquireError> {
+        self.try_take(permits).map(|()| OwnedSemaphorePermit {
+            semaphore: Arc::clone(self),
+            permits,
+        })
+    }
+
+    fn acquire_owned(&self, permits: usize) -> FutureAcquireOwned {
+        FutureAcquireOwned {
+            semaphore: Arc::clone(self),
+            permits,
+            waiter: WaitNode::new(WaitKind::Permits(permits)),
+            done: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tokio::runtime::Runtime as ThreadpoolRuntime;
+    use tokio::future::FutureExt;
+
+    use crate::timeout::tests::ManualTimer;
+    use crate::wakers::tests::counting_waker;
+
+    use super::{AcquireError, FutureAcquirableOwned, Semaphore, TryAcquireError};
+
+    #[test]
+    fn try_acquire() {
+        let semaphore = Semaphore::new(3);
+        let two = semaphore.try_acquire(2).unwrap();
+        assert_eq!(semaphore.try_acquire(2).err(), Some(TryAcquireError::NoPermits));
+        let one = semaphore.try_acquire(1).unwrap();
+        assert_eq!(semaphore.available_permits(), 0);
+        drop(two);
+        assert_eq!(semaphore.available_permits(), 2);
+        one.forget();
+        assert_eq!(semaphore.available_permits(), 2);
+        semaphore.add_permits(1);
+        assert_eq!(semaphore.available_permits(), 3);
+    }
+
+    #[test]
+    fn large_request_not_starved() {
+        let semaphore = Semaphore::new(2);
+        let held = semaphore.try_acquire(1).unwrap();
+
+        let (waker, count) = counting_waker();
+        let mut large = Box::pin(semaphore.acquire(2));
+        assert!(large.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        // a permit is free, but the large request came first
+        assert_eq!(semaphore.try_acquire(1).err(), Some(TryAcquireError::NoPermits));
+        let (waker2, count2) = counting_waker();
+        let mut small = Box::pin(semaphore.acquire(1));
+        assert!(small.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+
+        drop(held);
+        assert_eq!((count.count(), count2.count()), (1, 0));
+        let permit = match large.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(Ok(permit)) => permit,
+            _ => panic!("large request not granted"),
+        };
+        assert_eq!(permit.permits(), 2);
+        drop(permit);
+        assert_eq!(count2.count(), 1);
+        assert!(small.as_mut().poll(&mut Context::from_waker(&waker2)).is_ready());
+    }
+
+    #[test]
+    fn cancelled_head_lets_others_in() {
+        let semaphore = Semaphore::new(1);
+
+        let (waker, _) = counting_waker();
+        let mut large = Box::pin(semaphore.acquire(2));
+        assert!(large.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        let (waker2, count2) = counting_waker();
+        let mut small = Box::pin(semaphore.acquire(1));
+        assert!(small.as_mut().poll(&mut Context::from_waker(&waker2)).is_pending());
+
+        drop(large);
+        assert_eq!(count2.count(), 1);
+        assert!(small.as_mut().poll(&mut Context::from_waker(&waker2)).is_ready());
+    }
+
+    #[test]
+    fn cancelled_granted_waiter_gives_back() {
+        let semaphore = Semaphore::new(0);
+
+        let (waker, count) = counting_waker();
+        let mut waiting = Box::pin(semaphore.acquire(2));
+        assert!(waiting.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        semaphore.add_permits(2);
+        assert_eq!(count.count(), 1);
+        assert_eq!(semaphore.available_permits(), 0);
+        drop(waiting);
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn close_wakes_waiters() {
+        let semaphore = Semaphore::new(0);
+
+        let (waker, count) = counting_waker();
+        let mut waiting = Box::pin(semaphore.acquire(1));
+        assert!(waiting.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        semaphore.close();
+        assert_eq!(count.count(), 1);
+        match waiting.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(Err(AcquireError)) => {},
+            _ => panic!("closed semaphore gave permits"),
+        }
+        semaphore.add_permits(1);
+        assert_eq!(semaphore.try_acquire(1).err(), Some(TryAcquireError::Closed));
+        let mut late = Box::pin(semaphore.acquire(1));
+        match late.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(Err(AcquireError)) => {},
+            _ => panic!("closed semaphore gave permits"),
+        };
+    }
+
+    #[test]
+    fn timed_acquire() {
+        let semaphore = Semaphore::new(0);
+        let timer = ManualTimer::default();
+        let runtime = ThreadpoolRuntime::new().unwrap();
+
+        let (waker, _) = counting_waker();
+        let mut waiting = Box::pin(semaphore.acquire_for(1, Duration::from_secs(1), &timer));
+        assert!(waiting.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        timer.fire();
+        assert!(runtime.block_on(waiting).is_none());
+        semaphore.add_permits(1);
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn multithread_rate_limit() {
+        env_logger::try_init().ok();
+
+        let semaphore = Arc::new(Semaphore::new(3));
+        let running = Arc::new(AtomicUsize::new(0));
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        let inner = Arc::clone(&semaphore);
+        runtime.block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(20);
+            for i in 0..20 {
+                let semaphore = Arc::clone(&inner);
+                let running = Arc::clone(&running);
+                let mut tx = tx.clone();
+                tokio::spawn(async move {
+                    let permits = i % 3 + 1;
+                    let permit = semaphore.acquire_owned(permits).await.unwrap();
+                    assert!(running.fetch_add(permits, Ordering::SeqCst) + permits <= 3);
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    running.fetch_sub(permits, Ordering::SeqCst);
+                    drop(permit);
+                    tx.send(()).await.ok();
+                });
+            }
+            for _ in 0..20 {
+                rx.recv().timeout(Duration::from_secs(5)).await.expect("task never completed");
+            }
+        });
+        assert_eq!(semaphore.available_permits(), 3);
+    }
+}
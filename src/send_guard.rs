@@ -0,0 +1,223 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! parking_lot raw locks whose guards can be sent to other threads
+//!
+//! parking_lot's locks don't care which thread unlocks them, their guards are `!Send` only because of
+//! parking_lot's `deadlock_detection` feature, which tracks held locks per thread.
+//! Don't enable `send_guard` together with parking_lot's `deadlock_detection`.
+
+use lock_api::{
+    GuardSend,
+    RawMutex as RawMutexTrait, RawMutexFair,
+    RawRwLock as RawRwLockTrait, RawRwLockDowngrade, RawRwLockUpgrade, RawRwLockUpgradeDowngrade,
+};
+
+/// parking_lot::RawMutex with Send guards
+pub struct RawMutex(parking_lot::RawMutex);
+
+unsafe impl RawMutexTrait for RawMutex {
+    type GuardMarker = GuardSend;
+
+    const INIT: RawMutex = RawMutex(parking_lot::RawMutex::INIT);
+
+    fn lock(&self) {
+        self.0.lock();
+    }
+
+    fn try_lock(&self) -> bool {
+        self.0.try_lock()
+    }
+
+    fn unlock(&self) {
+        self.0.unlock();
+    }
+}
+
+unsafe impl RawMutexFair for RawMutex {
+    fn unlock_fair(&self) {
+        self.0.unlock_fair();
+    }
+
+    fn bump(&self) {
+        self.0.bump();
+    }
+}
+
+/// parking_lot::RawRwLock with Send guards
+pub struct RawRwLock(parking_lot::RawRwLock);
+
+unsafe impl RawRwLockTrait for RawRwLock {
+    type GuardMarker = GuardSend;
+
+    const INIT: RawRwLock = RawRwLock(parking_lot::RawRwLock::INIT);
+
+    fn lock_shared(&self) {
+        self.0.lock_shared();
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.0.try_lock_shared()
+    }
+
+    fn unlock_shared(&self) {
+        self.0.unlock_shared();
+    }
+
+    fn lock_exclusive(&self) {
+        self.0.lock_exclusive();
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.0.try_lock_exclusive()
+    }
+
+    fn unlock_exclusive(&self) {
+        self.0.unlock_exclusive();
+    }
+}
+
+unsafe impl RawRwLockUpgrade for RawRwLock {
+    fn lock_upgradable(&self) {
+        self.0.lock_upgradable();
+    }
+
+    fn try_lock_upgradable(&self) -> bool {
+        self.0.try_lock_upgradable()
+    }
+
+    fn unlock_upgradable(&self) {
+        self.0.unlock_upgradable();
+    }
+
+    fn upgrade(&self) {
+        self.0.upgrade();
+    }
+
+    fn try_upgrade(&self) -> bool {
+        self.0.try_upgrade()
+    }
+}
+
+unsafe impl RawRwLockDowngrade for RawRwLock {
+    fn downgrade(&self) {
+        self.0.downgrade();
+    }
+}
+
+unsafe impl RawRwLockUpgradeDowngrade for RawRwLock {
+    fn downgrade_upgradable(&self) {
+        self.0.downgrade_upgradable();
+    }
+
+    fn downgrade_to_upgradable(&self) {
+        self.0.downgrade_to_upgradable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::runtime::Runtime as ThreadpoolRuntime;
+    use tokio::runtime::current_thread::Runtime as CurrentThreadRuntime;
+    use tokio::future::FutureExt;
+
+    use crate::mutex::{FairMutex, FutureLockable, FutureLockableOwned, Mutex};
+    use crate::rwlock::{future_upgrade, FutureReadable, FutureReadableOwned, FutureUpgradableReadable, FutureUpgradableReadableOwned, FutureWriteableOwned, RwLock};
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[test]
+    fn guards_are_send() {
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+
+        let mutex = Arc::new(Mutex::new(0));
+        assert_send(&mutex.lock());
+        assert_send(&runtime.block_on(mutex.future_lock_owned()));
+
+        let fair = Arc::new(FairMutex::new(0));
+        assert_send(&fair.lock());
+        assert_send(&runtime.block_on(fair.future_lock_owned()));
+
+        let rwlock = Arc::new(RwLock::new(0));
+        assert_send(&rwlock.read());
+        assert_send(&runtime.block_on(rwlock.future_read_owned()));
+        assert_send(&rwlock.write());
+        assert_send(&runtime.block_on(rwlock.future_write_owned()));
+        assert_send(&rwlock.upgradable_read());
+        assert_send(&runtime.block_on(rwlock.future_upgradable_read_owned()));
+    }
+
+    #[test]
+    // holding the guard across an await is exactly what this feature is for
+    #[allow(clippy::await_holding_lock)]
+    fn multithread_guard_across_await() {
+        env_logger::try_init().ok();
+
+        let mutex = Arc::new(Mutex::new(0));
+        let rwlock = Arc::new(RwLock::new(0));
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        runtime.block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(6);
+            for _ in 0..2 {
+                let mutex = Arc::clone(&mutex);
+                let mut tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut guard = mutex.future_lock().await;
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    *guard += 1;
+                    drop(guard);
+                    let mut guard = mutex.future_lock_owned().await;
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    *guard += 1;
+                    drop(guard);
+                    tx.send(()).await.ok();
+                });
+            }
+            for _ in 0..2 {
+                let rwlock = Arc::clone(&rwlock);
+                let mut tx = tx.clone();
+                tokio::spawn(async move {
+                    let guard = rwlock.future_upgradable_read().await;
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    let mut guard = future_upgrade(guard).await;
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    *guard += 1;
+                    drop(guard);
+                    let guard = rwlock.future_read().await;
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    drop(guard);
+                    tx.send(()).await.ok();
+                });
+            }
+            for _ in 0..2 {
+                let rwlock = Arc::clone(&rwlock);
+                let mut tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut guard = rwlock.future_write_owned().await;
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    *guard += 1;
+                    drop(guard);
+                    let guard = rwlock.future_upgradable_read_owned().await;
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    drop(guard);
+                    let guard = rwlock.future_read_owned().await;
+                    tokio::timer::delay_for(Duration::from_millis(1)).await;
+                    drop(guard);
+                    tx.send(()).await.ok();
+                });
+            }
+            for _ in 0..6 {
+                rx.recv().timeout(Duration::from_secs(5)).await.expect("task never completed");
+            }
+            assert_eq!(*mutex.future_lock().await, 4);
+            assert_eq!(*rwlock.future_read().await, 4);
+        });
+    }
+}
@@ -0,0 +1,526 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::task::{Poll, Context};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use lock_api::{MappedRwLockReadGuard, MappedRwLockWriteGuard, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::rwlock::{FutureRawRwLock, FutureReadable, FutureReadableOwned, FutureWriteable, FutureWriteableOwned, RawRwLock_, RwLock};
+use crate::rwlock::owned::{
+    FutureReadOwned, FutureWriteOwned,
+    OwnedMappedRwLockReadGuard, OwnedMappedRwLockWriteGuard, OwnedRwLockReadGuard, OwnedRwLockWriteGuard,
+};
+use crate::rwlock::read::FutureRead;
+use crate::rwlock::write::FutureWrite;
+
+type Shard<K, V, S> = RwLock<HashMap<K, V, S>>;
+type ShardWriteGuard<'a, K, V, S> = RwLockWriteGuard<'a, FutureRawRwLock<RawRwLock_>, HashMap<K, V, S>>;
+
+/// Read reference to a value of a ShardedMap, holding its shard read-locked
+pub type Ref<'a, V> = MappedRwLockReadGuard<'a, FutureRawRwLock<RawRwLock_>, V>;
+/// Write reference to a value of a ShardedMap, holding its shard write-locked
+pub type RefMut<'a, V> = MappedRwLockWriteGuard<'a, FutureRawRwLock<RawRwLock_>, V>;
+/// Read reference to a value of a ShardedMap that keeps its shard alive, holding it read-locked
+pub type OwnedRef<K, V, S = RandomState> = OwnedMappedRwLockReadGuard<RawRwLock_, HashMap<K, V, S>, V>;
+/// Write reference to a value of a ShardedMap that keeps its shard alive, holding it write-locked
+pub type OwnedRefMut<K, V, S = RandomState> = OwnedMappedRwLockWriteGuard<RawRwLock_, HashMap<K, V, S>, V>;
+
+/// A concurrent HashMap split over several Future-compatible RwLocks
+///
+/// Each key belongs to a single shard, so tasks working on keys of different shards don't wait for each other.
+pub struct ShardedMap<K, V, S = RandomState> {
+    // Arc'd for the owned references
+    shards: Box<[Arc<Shard<K, V, S>>]>,
+    hasher: S,
+}
+
+impl<K: Eq + Hash, V> ShardedMap<K, V, RandomState> {
+    /// Creates an empty ShardedMap, with a number of shards depending on the available parallelism
+    pub fn new() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        ShardedMap::with_shards((cpus * 4).next_power_of_two())
+    }
+
+    /// Creates an empty ShardedMap with `shards` shards
+    pub fn with_shards(shards: usize) -> Self {
+        ShardedMap::with_shards_and_hasher(shards, RandomState::new())
+    }
+}
+
+impl<K: Eq + Hash, V> Default for ShardedMap<K, V, RandomState> {
+    fn default() -> Self {
+        ShardedMap::new()
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher + Clone> ShardedMap<K, V, S> {
+    /// Creates an empty ShardedMap with `shards` shards, using `hasher` to hash the keys
+    ///
+    /// Panics if `shards` is zero.
+    pub fn with_shards_and_hasher(shards: usize, hasher: S) -> Self {
+        assert!(shards > 0, "a ShardedMap needs at least one shard");
+        ShardedMap {
+            shards: (0..shards).map(|_| Arc::new(RwLock::new(HashMap::with_hasher(hasher.clone())))).collect(),
+            hasher,
+        }
+    }
+
+    /// Returns the number of shards
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_index<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        // the shard's HashMap uses the lowest and highest bits of the same hash, pick the shard with the middle ones
+        ((self.hasher.hash_one(key) >> 32) as usize) % self.shards.len()
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> &Arc<Shard<K, V, S>> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    /// Returns a read reference to the value of the key, without blocking
+    pub fn get<'a, Q>(&'a self, key: &'a Q) -> FutureGet<'a, K, V, S, Q>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        FutureGet {
+            lock: self.shard(key).future_read(),
+            key,
+        }
+    }
+
+    /// Returns a write reference to the value of the key, without blocking
+    pub fn get_mut<'a, Q>(&'a self, key: &'a Q) -> FutureGetMut<'a, K, V, S, Q>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        FutureGetMut {
+            lock: self.shard(key).future_write(),
+            key,
+        }
+    }
+
+    /// Returns a read reference to the value of the key without blocking,
+    /// the reference keeps its shard alive so it can outlive the map's borrow, or the map itself
+    pub fn get_owned<'a, Q>(&self, key: &'a Q) -> FutureGetOwned<'a, K, V, S, Q>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        FutureGetOwned {
+            lock: self.shard(key).future_read_owned(),
+            key,
+        }
+    }
+
+    /// Returns a write reference to the value of the key without blocking,
+    /// the reference keeps its shard alive so it can outlive the map's borrow, or the map itself
+    pub fn get_mut_owned<'a, Q>(&self, key: &'a Q) -> FutureGetMutOwned<'a, K, V, S, Q>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        FutureGetMutOwned {
+            lock: self.shard(key).future_write_owned(),
+            key,
+        }
+    }
+
+    /// Inserts a value without blocking, returning the previous one
+    pub fn insert(&self, key: K, value: V) -> FutureInsert<'_, K, V, S> {
+        FutureInsert {
+            lock: self.shard(&key).future_write(),
+            item: Some((key, value)),
+        }
+    }
+
+    /// Removes a key without blocking, returning its value
+    pub fn remove<'a, Q>(&'a self, key: &'a Q) -> FutureRemove<'a, K, V, S, Q>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        FutureRemove {
+            lock: self.shard(key).future_write(),
+            key,
+        }
+    }
+
+    /// Returns the entry of the key for in-place manipulation, without blocking
+    ///
+    /// The entry keeps its shard write-locked.
+    pub fn entry(&self, key: K) -> FutureEntry<'_, K, V, S> {
+        FutureEntry {
+            lock: self.shard(&key).future_write(),
+            key: Some(key),
+        }
+    }
+}
+
+impl<K, V, S> fmt::Debug for ShardedMap<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShardedMap").field("shards", &self.shards.len()).finish()
+    }
+}
+
+/// Wrapper to get a value of a ShardedMap in Future-style
+pub struct FutureGet<'a, K, V, S, Q: ?Sized> {
+    lock: FutureRead<'a, RawRwLock_, HashMap<K, V, S>>,
+    key: &'a Q,
+}
+
+impl<'a, K, V, S, Q> Future for FutureGet<'a, K, V, S, Q>
+where
+    K: Eq + Hash + Borrow<Q>,
+    S: BuildHasher,
+    Q: Eq + Hash + ?Sized,
+{
+    type Output = Option<Ref<'a, V>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // lock is structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let key = this.key;
+        unsafe { Pin::new_unchecked(&mut this.lock) }.poll(cx)
+            .map(|guard| RwLockReadGuard::try_map(guard, |map| map.get(key)).ok())
+    }
+}
+
+/// Wrapper to get a value of a ShardedMap for writing in Future-style
+pub struct FutureGetMut<'a, K, V, S, Q: ?Sized> {
+    lock: FutureWrite<'a, RawRwLock_, HashMap<K, V, S>>,
+    key: &'a Q,
+}
+
+impl<'a, K, V, S, Q> Future for FutureGetMut<'a, K, V, S, Q>
+where
+    K: Eq + Hash + Borrow<Q>,
+    S: BuildHasher,
+    Q: Eq + Hash + ?Sized,
+{
+    type Output = Option<RefMut<'a, V>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // lock is structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let key = this.key;
+        unsafe { Pin::new_unchecked(&mut this.lock) }.poll(cx)
+            .map(|guard| RwLockWriteGuard::try_map(guard, |map| map.get_mut(key)).ok())
+    }
+}
+
+/// Wrapper to get a value of a ShardedMap as an owned reference in Future-style
+pub struct FutureGetOwned<'a, K, V, S, Q: ?Sized> {
+    lock: FutureReadOwned<RawRwLock_, HashMap<K, V, S>>,
+    key: &'a Q,
+}
+
+impl<'a, K, V, S, Q> Future for FutureGetOwned<'a, K, V, S, Q>
+where
+    K: Eq + Hash + Borrow<Q>,
+    S: BuildHasher,
+    Q: Eq + Hash + ?Sized,
+{
+    type Output = Option<OwnedRef<K, V, S>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // lock is structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let key = this.key;
+        unsafe { Pin::new_unchecked(&mut this.lock) }.poll(cx)
+            .map(|guard| OwnedRwLockReadGuard::try_map(guard, |map| map.get(key)).ok())
+    }
+}
+
+/// Wrapper to get a value of a ShardedMap for writing as an owned reference in Future-style
+pub struct FutureGetMutOwned<'a, K, V, S, Q: ?Sized> {
+    lock: FutureWriteOwned<RawRwLock_, HashMap<K, V, S>>,
+    key: &'a Q,
+}
+
+impl<'a, K, V, S, Q> Future for FutureGetMutOwned<'a, K, V, S, Q>
+where
+    K: Eq + Hash + Borrow<Q>,
+    S: BuildHasher,
+    Q: Eq + Hash + ?Sized,
+{
+    type Output = Option<OwnedRefMut<K, V, S>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // lock is structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let key = this.key;
+        unsafe { Pin::new_unchecked(&mut this.lock) }.poll(cx)
+            .map(|guard| OwnedRwLockWriteGuard::try_map(guard, |map| map.get_mut(key)).ok())
+    }
+}
+
+/// Wrapper to insert in a ShardedMap in Future-style
+pub struct FutureInsert<'a, K, V, S> {
+    lock: FutureWrite<'a, RawRwLock_, HashMap<K, V, S>>,
+    item: Option<(K, V)>,
+}
+
+impl<'a, K, V, S> Future for FutureInsert<'a, K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    type Output = Option<V>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // lock is structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let item = &mut this.item;
+        unsafe { Pin::new_unchecked(&mut this.lock) }.poll(cx)
+            .map(|mut guard| {
+                let (key, value) = item.take().expect("polled after completion");
+                guard.insert(key, value)
+            })
+    }
+}
+
+/// Wrapper to remove from a ShardedMap in Future-style
+pub struct FutureRemove<'a, K, V, S, Q: ?Sized> {
+    lock: FutureWrite<'a, RawRwLock_, HashMap<K, V, S>>,
+    key: &'a Q,
+}
+
+impl<'a, K, V, S, Q> Future for FutureRemove<'a, K, V, S, Q>
+where
+    K: Eq + Hash + Borrow<Q>,
+    S: BuildHasher,
+    Q: Eq + Hash + ?Sized,
+{
+    type Output = Option<V>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // lock is structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let key = this.key;
+        unsafe { Pin::new_unchecked(&mut this.lock) }.poll(cx)
+            .map(|mut guard| guard.remove(key))
+    }
+}
+
+/// Wrapper to get an Entry of a ShardedMap in Future-style
+pub struct FutureEntry<'a, K, V, S> {
+    lock: FutureWrite<'a, RawRwLock_, HashMap<K, V, S>>,
+    key: Option<K>,
+}
+
+impl<'a, K, V, S> Future for FutureEntry<'a, K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    type Output = Entry<'a, K, V, S>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // lock is structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let key = &mut this.key;
+        unsafe { Pin::new_unchecked(&mut this.lock) }.poll(cx)
+            .map(|guard| Entry {
+                guard,
+                key: key.take().expect("polled after completion"),
+            })
+    }
+}
+
+/// A key of a ShardedMap, with its shard write-locked
+pub struct Entry<'a, K, V, S> {
+    guard: ShardWriteGuard<'a, K, V, S>,
+    key: K,
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher> Entry<'a, K, V, S> {
+    /// Returns the key of the entry
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns the value of the entry, if any
+    pub fn get(&self) -> Option<&V> {
+        self.guard.get(&self.key)
+    }
+
+    /// Returns the value of the entry for writing, if any
+    pub fn get_mut(&mut self) -> Option<&mut V> {
+        self.guard.get_mut(&self.key)
+    }
+
+    /// Modifies the value of the entry, if any
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Some(value) = self.get_mut() {
+            f(value);
+        }
+        self
+    }
+
+    /// Inserts `default` if the entry is empty, and returns a write reference to the value
+    pub fn or_insert(self, default: V) -> RefMut<'a, V> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Inserts the result of `default` if the entry is empty, and returns a write reference to the value
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> RefMut<'a, V> {
+        let Entry { guard, key } = self;
+        RwLockWriteGuard::map(guard, |map| map.entry(key).or_insert_with(default))
+    }
+
+    /// Inserts the default value if the entry is empty, and returns a write reference to the value
+    pub fn or_default(self) -> RefMut<'a, V>
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// Sets the value of the entry, returning the previous one
+    pub fn insert(&mut self, value: V) -> Option<V>
+    where
+        K: Clone,
+    {
+        self.guard.insert(self.key.clone(), value)
+    }
+
+    /// Removes the entry, returning its value
+    pub fn remove(mut self) -> Option<V> {
+        self.guard.remove(&self.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tokio::runtime::Runtime as ThreadpoolRuntime;
+    use tokio::runtime::current_thread::Runtime as CurrentThreadRuntime;
+    use tokio::future::FutureExt;
+
+    use crate::wakers::tests::counting_waker;
+
+    use super::ShardedMap;
+
+    #[test]
+    fn map_operations() {
+        let map = ShardedMap::with_shards(4);
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        runtime.block_on(async {
+            assert_eq!(map.insert("a".to_owned(), 1).await, None);
+            assert_eq!(map.insert("a".to_owned(), 2).await, Some(1));
+            assert_eq!(map.get("a").await.map(|v| *v), Some(2));
+            assert!(map.get("b").await.is_none());
+            *map.get_mut("a").await.expect("value missing") += 1;
+
+            *map.entry("a".to_owned()).await.and_modify(|v| *v *= 10).or_insert(0) += 1;
+            *map.entry("b".to_owned()).await.or_default() += 5;
+            assert_eq!(map.get("a").await.map(|v| *v), Some(31));
+            assert_eq!(map.get("b").await.map(|v| *v), Some(5));
+
+            assert_eq!(map.remove("a").await, Some(31));
+            assert_eq!(map.entry("b".to_owned()).await.remove(), Some(5));
+            assert!(map.get("a").await.is_none() && map.get("b").await.is_none());
+        });
+    }
+
+    #[test]
+    fn shards_are_independent() {
+        let map = ShardedMap::with_shards(2);
+        let first = 0;
+        let same = (1..).find(|k| map.shard_index(k) == map.shard_index(&first)).unwrap();
+        let other = (1..).find(|k| map.shard_index(k) != map.shard_index(&first)).unwrap();
+
+        let (waker, count) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+        let entry = match Box::pin(map.entry(first)).as_mut().poll(&mut cx) {
+            Poll::Ready(entry) => entry,
+            Poll::Pending => panic!("shard not locked"),
+        };
+        assert!(Box::pin(map.insert(other, 1)).as_mut().poll(&mut cx).is_ready());
+        let mut blocked = Box::pin(map.insert(same, 1));
+        assert!(blocked.as_mut().poll(&mut cx).is_pending());
+        drop(entry.or_insert(0));
+        assert_eq!(count.count(), 1);
+        assert!(blocked.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn owned_refs_held_across_await() {
+        env_logger::try_init().ok();
+
+        let map = Arc::new(ShardedMap::with_shards(4));
+        let other = (2..).find(|k| map.shard_index(k) != map.shard_index(&1)).unwrap();
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        runtime.block_on(async {
+            map.insert(1, 10).await;
+            map.insert(other, 20).await;
+        });
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let inner = Arc::clone(&map);
+        runtime.spawn(async move {
+            assert!(inner.get_owned(&0).await.is_none());
+            let mut value = inner.get_mut_owned(&1).await.expect("value missing");
+            let second = inner.get_owned(&other).await.expect("value missing");
+            // the task's handle on the map is gone, the references held across an await keep their shards alive
+            drop(inner);
+            tokio::timer::delay_for(Duration::from_millis(1)).await;
+            *value += *second;
+            tx.send(()).ok();
+        });
+        runtime.block_on(async {
+            rx.await.expect("task never completed");
+            assert_eq!(map.get(&1).await.map(|v| *v), Some(30));
+        });
+    }
+
+    #[test]
+    fn multithread_counters() {
+        env_logger::try_init().ok();
+
+        let map = Arc::new(ShardedMap::new());
+        let runtime = ThreadpoolRuntime::new().unwrap();
+        let inner = Arc::clone(&map);
+        runtime.block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+            for _ in 0..10 {
+                let map = Arc::clone(&inner);
+                let mut tx = tx.clone();
+                tokio::spawn(async move {
+                    for key in 0..100 {
+                        *map.entry(key).await.or_insert(0) += 1;
+                    }
+                    tx.send(()).await.ok();
+                });
+            }
+            for _ in 0..10 {
+                rx.recv().timeout(Duration::from_secs(5)).await.expect("task never completed");
+            }
+        });
+        let mut runtime = CurrentThreadRuntime::new().unwrap();
+        runtime.block_on(async {
+            for key in 0..100 {
+                assert_eq!(map.get(&key).await.map(|v| *v), Some(10));
+            }
+        });
+    }
+}
@@ -0,0 +1,266 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use lock_api::{Mutex, RawMutex, RawRwLock, RwLock};
+
+use crate::mutex::{Fairness, FutureRawMutex};
+use crate::rwlock::FutureRawRwLock;
+
+/// Upper bounds of the hold time histogram buckets, the last bucket counts the longer holds
+pub const HOLD_TIME_BUCKETS: [Duration; 7] = [
+    Duration::from_micros(1),
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+const BUCKETS: usize = HOLD_TIME_BUCKETS.len() + 1;
+
+/// A snapshot of the statistics of a lock
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LockStats {
+    /// Number of times the lock has been acquired
+    pub acquisitions: u64,
+    /// Number of acquisitions by a Future that had to wait
+    pub contended: u64,
+    /// Total time Futures waited for the lock
+    pub total_wait: Duration,
+    /// Longest time a Future waited for the lock
+    pub max_wait: Duration,
+    /// Number of Futures currently waiting
+    pub queue_len: usize,
+    /// Number of readers currently holding the lock, always zero for Mutexes
+    pub readers: usize,
+    /// Number of holds by duration, see `HOLD_TIME_BUCKETS`
+    ///
+    /// For RwLocks readers are counted together, from the first one in to the last one out.
+    pub hold_time_histogram: [u64; BUCKETS],
+}
+
+/// Trait to read the statistics of a wrapped lock
+pub trait Stats {
+    /// Returns a snapshot of the lock statistics
+    fn stats(&self) -> LockStats;
+}
+
+impl<R: RawMutex, T: ?Sized, F: Fairness> Stats for Mutex<FutureRawMutex<R, F>, T> {
+    fn stats(&self) -> LockStats {
+        unsafe { self.raw() }.stats()
+    }
+}
+
+impl<R: RawRwLock, T: ?Sized> Stats for RwLock<FutureRawRwLock<R>, T> {
+    fn stats(&self) -> LockStats {
+        unsafe { self.raw() }.stats()
+    }
+}
+
+// nanoseconds since the first call, so instants fit in an atomic
+fn now() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+/// Wait statistics, updated by the WaiterQueue
+pub(crate) struct WaitStats {
+    contended: AtomicU64,
+    total_wait: AtomicU64,
+    max_wait: AtomicU64,
+}
+
+impl WaitStats {
+    pub(crate) const fn new() -> WaitStats {
+        WaitStats {
+            contended: AtomicU64::new(0),
+            total_wait: AtomicU64::new(0),
+            max_wait: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn waited(&self, wait: Duration) {
+        let wait = wait.as_nanos() as u64;
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.total_wait.fetch_add(wait, Ordering::Relaxed);
+        self.max_wait.fetch_max(wait, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fill(&self, stats: &mut LockStats) {
+        stats.contended = self.contended.load(Ordering::Relaxed);
+        stats.total_wait = Duration::from_nanos(self.total_wait.load(Ordering::Relaxed));
+        stats.max_wait = Duration::from_nanos(self.max_wait.load(Ordering::Relaxed));
+    }
+}
+
+/// Acquisition and hold statistics, updated by the raw locks
+pub(crate) struct HoldStats {
+    acquisitions: AtomicU64,
+    locked_at: AtomicU64,
+    readers: AtomicUsize,
+    shared_since: AtomicU64,
+    holds: [AtomicU64; BUCKETS],
+}
+
+impl HoldStats {
+    pub(crate) const fn new() -> HoldStats {
+        HoldStats {
+            acquisitions: AtomicU64::new(0),
+            locked_at: AtomicU64::new(0),
+            readers: AtomicUsize::new(0),
+            shared_since: AtomicU64::new(0),
+            holds: [const { AtomicU64::new(0) }; BUCKETS],
+        }
+    }
+
+    fn held(&self, since: u64) {
+        let held = Duration::from_nanos(now().saturating_sub(since));
+        let bucket = HOLD_TIME_BUCKETS.iter().position(|b| held < *b).unwrap_or(BUCKETS - 1);
+        self.holds[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn shared_held(&self) {
+        if self.readers.fetch_add(1, Ordering::Relaxed) == 0 {
+            self.shared_since.store(now(), Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn exclusive_acquired(&self) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.locked_at.store(now(), Ordering::Relaxed);
+    }
+
+    /// Must be called before actually unlocking, while nobody else can lock
+    pub(crate) fn exclusive_released(&self) {
+        self.held(self.locked_at.load(Ordering::Relaxed));
+    }
+
+    pub(crate) fn shared_acquired(&self) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.shared_held();
+    }
+
+    pub(crate) fn shared_released(&self) {
+        // racing readers can skew the shared hold time a bit, it's a statistic
+        if self.readers.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.held(self.shared_since.load(Ordering::Relaxed));
+        }
+    }
+
+    /// An exclusive lock becoming shared
+    pub(crate) fn downgraded(&self) {
+        self.exclusive_released();
+        self.shared_held();
+    }
+
+    /// A shared lock becoming exclusive
+    pub(crate) fn upgraded(&self) {
+        self.shared_released();
+        self.locked_at.store(now(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn fill(&self, stats: &mut LockStats) {
+        stats.acquisitions = self.acquisitions.load(Ordering::Relaxed);
+        stats.readers = self.readers.load(Ordering::Relaxed);
+        for (count, hold) in stats.hold_time_histogram.iter_mut().zip(self.holds.iter()) {
+            *count = hold.load(Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::task::Context;
+
+    use crate::mutex::{FutureLockable, Mutex};
+    use crate::rwlock::{FutureUpgradableReadable, RwLock};
+    use crate::wakers::tests::counting_waker;
+
+    use lock_api::{RwLockUpgradableReadGuard, RwLockWriteGuard};
+
+    use super::Stats;
+
+    #[test]
+    fn mutex_stats() {
+        let lock = Mutex::new(0);
+        drop(lock.lock());
+        let guard = lock.try_lock().expect("lock busy");
+
+        let (waker, _) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut waiter = Box::pin(lock.future_lock());
+        assert!(waiter.as_mut().poll(&mut cx).is_pending());
+        let stats = lock.stats();
+        assert_eq!((stats.acquisitions, stats.contended, stats.queue_len), (2, 0, 1));
+
+        drop(guard);
+        assert!(waiter.as_mut().poll(&mut cx).is_ready());
+        let stats = lock.stats();
+        assert_eq!((stats.acquisitions, stats.contended, stats.queue_len), (3, 1, 0));
+        assert_eq!(stats.max_wait, stats.total_wait);
+        // the last guard has been dropped with its Poll
+        assert_eq!(stats.hold_time_histogram.iter().sum::<u64>(), 3);
+        assert_eq!(stats.readers, 0);
+    }
+
+    #[test]
+    fn rwlock_stats() {
+        let lock = RwLock::new(0);
+        let first = lock.read();
+        let second = lock.read();
+        assert_eq!(lock.stats().readers, 2);
+        drop(first);
+        drop(second);
+        let stats = lock.stats();
+        // both readers are a single shared hold
+        assert_eq!((stats.acquisitions, stats.readers), (2, 0));
+        assert_eq!(stats.hold_time_histogram.iter().sum::<u64>(), 1);
+
+        let (waker, _) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+        let upgradable = match Box::pin(lock.future_upgradable_read()).as_mut().poll(&mut cx) {
+            std::task::Poll::Ready(guard) => guard,
+            std::task::Poll::Pending => panic!("lock busy"),
+        };
+        assert_eq!(lock.stats().readers, 1);
+        let writer = RwLockUpgradableReadGuard::upgrade(upgradable);
+        assert_eq!(lock.stats().readers, 0);
+        drop(writer);
+        let stats = lock.stats();
+        assert_eq!((stats.acquisitions, stats.readers), (3, 0));
+        assert_eq!(stats.hold_time_histogram.iter().sum::<u64>(), 3);
+    }
+
+    #[test]
+    fn rwlock_downgrade_stats() {
+        let lock = RwLock::new(0);
+
+        // write, then upgradable read, then read: one exclusive and one shared hold
+        let upgradable = RwLockWriteGuard::downgrade_to_upgradable(lock.write());
+        assert_eq!(lock.stats().readers, 1);
+        let reader = RwLockUpgradableReadGuard::downgrade(upgradable);
+        assert_eq!(lock.stats().readers, 1);
+        drop(reader);
+        let stats = lock.stats();
+        assert_eq!((stats.acquisitions, stats.readers), (1, 0));
+        assert_eq!(stats.hold_time_histogram.iter().sum::<u64>(), 2);
+
+        // upgradable read, then read: a single shared hold
+        let reader = RwLockUpgradableReadGuard::downgrade(lock.upgradable_read());
+        assert_eq!(lock.stats().readers, 1);
+        drop(reader);
+        let stats = lock.stats();
+        assert_eq!((stats.acquisitions, stats.readers), (2, 0));
+        assert_eq!(stats.hold_time_histogram.iter().sum::<u64>(), 3);
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cell::Cell;
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Poll, Context};
+use std::pin::Pin;
+
+thread_local! {
+    // the tracked task being polled or dropped by the current thread
+    static CURRENT: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Returns the id of the tracked task running on the current thread, if any
+pub(crate) fn current() -> Option<usize> {
+    CURRENT.with(Cell::get)
+}
+
+/// Restores the previously tracked task on drop
+struct Enter(Option<usize>);
+
+impl Enter {
+    fn new(task: usize) -> Enter {
+        Enter(CURRENT.with(|c| c.replace(Some(task))))
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        CURRENT.with(|c| c.set(self.0));
+    }
+}
+
+/// A future whose lock holds and waits are attributed to its own task
+pub struct Tracked<F> {
+    task_id: usize,
+    future: ManuallyDrop<F>,
+}
+
+impl<F> Tracked<F> {
+    /// Returns the id the task is reported with
+    pub fn task_id(&self) -> usize {
+        self.task_id
+    }
+}
+
+impl<F: Future> Future for Tracked<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // future is structurally pinned, only dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        let _enter = Enter::new(this.task_id);
+        unsafe { Pin::new_unchecked(&mut *this.future) }.poll(cx)
+    }
+}
+
+impl<F> Drop for Tracked<F> {
+    fn drop(&mut self) {
+        // guards and waiters dropped with the future still belong to the task
+        let _enter = Enter::new(self.task_id);
+        unsafe { ManuallyDrop::drop(&mut self.future); }
+    }
+}
+
+/// Tracks the locks held and waited for by a future, usually the whole body of a task
+///
+/// Futures can't tell which task is polling them, the deadlock detector only sees locks used inside tracked futures,
+/// the lock order validator falls back to the current thread.
+pub fn track<F: Future>(future: F) -> Tracked<F> {
+    Tracked {
+        task_id: NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed),
+        future: ManuallyDrop::new(future),
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::future::Future;
+use std::task::{Poll, Context};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+/// Source of the delays used by the timed lock futures, to keep the crate runtime-agnostic
+pub trait Timer {
+    /// Future that completes at the given deadline
+    type Delay: Future<Output = ()>;
+
+    /// Creates a Delay that completes at the given deadline
+    fn delay_until(&self, deadline: Instant) -> Self::Delay;
+}
+
+/// Timer implementation based on tokio's timer
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioTimer;
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioTimer {
+    type Delay = tokio::timer::Delay;
+
+    fn delay_until(&self, deadline: Instant) -> Self::Delay {
+        tokio::timer::delay(deadline)
+    }
+}
+
+/// Wrapper that gives up on a lock Future when a Delay completes, resolving to None
+pub struct FutureTimeout<F, D>
+where
+    F: Future,
+    D: Future<Output = ()>,
+{
+    future: Option<F>,
+    // None for a timeout too long to have a representable deadline
+    delay: Option<D>,
+}
+
+impl<F, D> FutureTimeout<F, D>
+where
+    F: Future,
+    D: Future<Output = ()>,
+{
+    pub(crate) fn new(future: F, delay: D) -> Self {
+        FutureTimeout {
+            future: Some(future),
+            delay: Some(delay),
+        }
+    }
+
+    /// Gives up after the given timeout, or never if the deadline would overflow `Instant`
+    pub(crate) fn after<Tm>(future: F, timeout: Duration, timer: &Tm) -> Self
+    where
+        Tm: Timer<Delay = D>,
+    {
+        FutureTimeout {
+            future: Some(future),
+            delay: Instant::now().checked_add(timeout).map(|deadline| timer.delay_until(deadline)),
+        }
+    }
+}
+
+impl<F, D> Future for FutureTimeout<F, D>
+where
+    F: Future,
+    D: Future<Output = ()>,
+{
+    type Output = Option<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // both fields are structurally pinned, future is only dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = this.future.as_mut().expect("FutureTimeout polled after completion");
+        if let Poll::Ready(guard) = unsafe { Pin::new_unchecked(future) }.poll(cx) {
+            this.future = None;
+            return Poll::Ready(Some(guard));
+        }
+        let delay = match this.delay.as_mut() {
+            Some(delay) => delay,
+            None => return Poll::Pending,
+        };
+        match unsafe { Pin::new_unchecked(delay) }.poll(cx) {
+            Poll::Ready(()) => {
+                // leave the waiters queue right now, not when we get dropped
+                this.future = None;
+                Poll::Ready(None)
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::{Context, Poll};
+    use std::time::Instant;
+
+    use super::Timer;
+
+    /// Timer whose delays complete only when fired by hand, whatever their deadline
+    #[derive(Default)]
+    pub(crate) struct ManualTimer(Arc<AtomicBool>);
+
+    impl ManualTimer {
+        pub(crate) fn fire(&self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) struct ManualDelay(Arc<AtomicBool>);
+
+    impl Future for ManualDelay {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+            if self.0.load(Ordering::SeqCst) {
+                Poll::Ready(())
+            }
+            else {
+                Poll::Pending
+            }
+        }
+    }
+
+    impl Timer for ManualTimer {
+        type Delay = ManualDelay;
+
+        fn delay_until(&self, _deadline: Instant) -> ManualDelay {
+            ManualDelay(Arc::clone(&self.0))
+        }
+    }
+}
@@ -0,0 +1,1028 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomPinned;
+#[cfg(any(feature = "lockdep", feature = "watchdog"))]
+use std::panic::Location;
+use std::ptr::null;
+use std::sync::atomic::{fence, AtomicU8, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+#[cfg(any(feature = "stats", feature = "tracing", feature = "deadlock_detection"))]
+use std::time::Instant;
+
+use lock_api::RawMutex as _;
+
+use parking_lot::RawMutex;
+
+#[cfg(feature = "stats")]
+use crate::stats::WaitStats;
+#[cfg(feature = "lockdep")]
+use crate::lockdep::LockClass;
+#[cfg(feature = "watchdog")]
+use crate::watchdog::HoldWatch;
+
+/// not in the list and no pending wakeup
+const IDLE: u8 = 0;
+/// linked in the list, waiting to be woken
+const QUEUED: u8 = 1;
+/// removed from the list by a wakeup that hasn't been consumed yet
+const NOTIFIED: u8 = 2;
+/// removed from the list by a fair unlock, the lock is being held on behalf of the node's owner
+const GRANTED: u8 = 3;
+
+thread_local! {
+    // the queue whose handed off lock is being claimed by the current thread
+    static CLAIMING: Cell<*const WaiterQueue> = const { Cell::new(null()) };
+}
+
+/// What a waiter is trying to acquire, decides which waiters get woken together
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WaitKind {
+    /// compatible with other Shared waiters and a single Upgradable one
+    Shared,
+    /// compatible with Shared waiters only
+    Upgradable,
+    /// compatible with nobody
+    Exclusive,
+    /// an upgradable reader waiting for the readers to leave, it holds the lock already so it goes first in line
+    Upgrade,
+    /// a Semaphore user waiting for the given number of permits, compatible with nobody
+    Permits(usize),
+}
+
+#[cfg(feature = "tracing")]
+impl WaitKind {
+    fn name(self) -> &'static str {
+        match self {
+            WaitKind::Shared => "shared",
+            WaitKind::Upgradable => "upgradable",
+            WaitKind::Exclusive => "exclusive",
+            WaitKind::Upgrade => "upgrade",
+            WaitKind::Permits(_) => "permits",
+        }
+    }
+}
+
+struct NodeInner {
+    prev: *const WaitNode,
+    next: *const WaitNode,
+    waker: Option<Waker>,
+    // when poll_acquire first failed
+    #[cfg(any(feature = "stats", feature = "tracing", feature = "deadlock_detection"))]
+    waiting_since: Option<Instant>,
+}
+
+/// A waiter registration, embedded in the waiting future
+///
+/// The node is only modified while holding the lock of the WaiterQueue it belongs to,
+/// and the future owning it unlinks it on drop, so the list never points to freed memory.
+pub(crate) struct WaitNode {
+    // written only under the queue lock, read without it to skip locking for idle nodes
+    state: AtomicU8,
+    kind: WaitKind,
+    inner: UnsafeCell<NodeInner>,
+    // where the waiting future has been created, for the lock order validation and the watchdog
+    #[cfg(any(feature = "lockdep", feature = "watchdog"))]
+    site: Option<&'static Location<'static>>,
+    _pin: PhantomPinned,
+}
+
+unsafe impl Send for WaitNode {}
+unsafe impl Sync for WaitNode {}
+
+impl WaitNode {
+    pub(crate) const fn new(kind: WaitKind) -> WaitNode {
+        WaitNode {
+            state: AtomicU8::new(IDLE),
+            kind,
+            inner: UnsafeCell::new(NodeInner {
+                prev: null(),
+                next: null(),
+                waker: None,
+                #[cfg(any(feature = "stats", feature = "tracing", feature = "deadlock_detection"))]
+                waiting_since: None,
+            }),
+            #[cfg(any(feature = "lockdep", feature = "watchdog"))]
+            site: None,
+            _pin: PhantomPinned,
+        }
+    }
+
+    /// Creates a node remembering where its future has been created, acquisitions are then validated by lockdep
+    /// if the queue has a lock class, and reported by the watchdog if held too long
+    #[cfg_attr(any(feature = "lockdep", feature = "watchdog"), track_caller)]
+    pub(crate) fn located(kind: WaitKind) -> WaitNode {
+        #[allow(unused_mut)]
+        let mut node = WaitNode::new(kind);
+        #[cfg(any(feature = "lockdep", feature = "watchdog"))]
+        {
+            node.site = Some(Location::caller());
+        }
+        node
+    }
+
+    fn state(&self) -> u8 {
+        self.state.load(Ordering::Acquire)
+    }
+
+    fn set_state(&self, state: u8) {
+        self.state.store(state, Ordering::Release);
+    }
+}
+
+const WAKE_LIST_SIZE: usize = 32;
+
+/// Wakers taken out of the list, woken only after the list lock has been released
+struct WakeList {
+    wakers: [Option<Waker>; WAKE_LIST_SIZE],
+    len: usize,
+}
+
+impl WakeList {
+    fn new() -> WakeList {
+        WakeList {
+            wakers: Default::default(),
+            len: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == WAKE_LIST_SIZE
+    }
+
+    fn push(&mut self, waker: Option<Waker>) {
+        self.wakers[self.len] = waker;
+        self.len += 1;
+    }
+
+    fn wake_all(&mut self) {
+        for w in self.wakers[..self.len].iter_mut() {
+            if let Some(w) = w.take() {
+                w.wake();
+            }
+        }
+        self.len = 0;
+    }
+}
+
+/// Waiters woken so far by a single release
+#[derive(Default)]
+struct Batch {
+    shared: bool,
+    upgradable: bool,
+    done: bool,
+}
+
+impl Batch {
+    /// Adds a waiter to the batch, if it can hold the lock together with the others
+    fn admit(&mut self, kind: WaitKind) -> bool {
+        match kind {
+            WaitKind::Exclusive | WaitKind::Upgrade | WaitKind::Permits(_) if !self.shared && !self.upgradable => {
+                // a writer is always alone
+                self.done = true;
+                true
+            },
+            WaitKind::Upgradable if !self.upgradable => {
+                self.upgradable = true;
+                true
+            },
+            WaitKind::Shared => {
+                self.shared = true;
+                true
+            },
+            _ => {
+                self.done = true;
+                false
+            },
+        }
+    }
+}
+
+struct List {
+    head: *const WaitNode,
+    tail: *const WaitNode,
+    // wakers of tasks polling without a node, woken all together on release
+    unpinned: Vec<Waker>,
+}
+
+/// Intrusive FIFO list of waiting futures, shared by FutureRawMutex and FutureRawRwLock
+///
+/// Registration happens under the same lock used to wake waiters, so a future can't register
+/// itself right after the lock holder released the lock and went looking for someone to wake.
+pub(crate) struct WaiterQueue {
+    lock: RawMutex,
+    // linked nodes plus futures about to link themselves, lets unlock skip the list when zero
+    waiting: AtomicUsize,
+    list: UnsafeCell<List>,
+    #[cfg(feature = "stats")]
+    pub(crate) stats: WaitStats,
+    // tags the tracing events, deadlock and watchdog reports
+    #[cfg(any(feature = "tracing", feature = "deadlock_detection", feature = "watchdog"))]
+    name: Option<&'static str>,
+    #[cfg(feature = "lockdep")]
+    class: Option<&'static LockClass>,
+    #[cfg(feature = "watchdog")]
+    watch: HoldWatch,
+}
+
+unsafe impl Send for WaiterQueue {}
+unsafe impl Sync for WaiterQueue {}
+
+/// Exclusive access to the list, released on drop
+struct ListGuard<'a> {
+    queue: &'a WaiterQueue,
+}
+
+impl<'a> Drop for ListGuard<'a> {
+    fn drop(&mut self) {
+        self.queue.lock.unlock();
+    }
+}
+
+impl<'a> ListGuard<'a> {
+    #[allow(clippy::mut_from_ref)]
+    fn list(&self) -> &mut List {
+        unsafe { &mut *self.queue.list.get() }
+    }
+
+    /// # Safety
+    /// node must not be linked, and must stay pinned until unlinked
+    unsafe fn push_back(&mut self, node: &WaitNode) {
+        let list = self.list();
+        let n = &mut *node.inner.get();
+        n.prev = list.tail;
+        n.next = null();
+        if list.tail.is_null() {
+            list.head = node;
+        }
+        else {
+            (*(*list.tail).inner.get()).next = node;
+        }
+        list.tail = node;
+        node.set_state(QUEUED);
+    }
+
+    /// # Safety
+    /// node must not be linked, and must stay pinned until unlinked
+    unsafe fn push_front(&mut self, node: &WaitNode) {
+        let list = self.list();
+        let n = &mut *node.inner.get();
+        n.prev = null();
+        n.next = list.head;
+        if list.head.is_null() {
+            list.tail = node;
+        }
+        else {
+            (*(*list.head).inner.get()).prev = node;
+        }
+        list.head = node;
+        node.set_state(QUEUED);
+    }
+
+    /// # Safety
+    /// node must be linked in this list
+    unsafe fn unlink(&mut self, node: &WaitNode) {
+        let list = self.list();
+        let n = &mut *node.inner.get();
+        if n.prev.is_null() {
+            list.head = n.next;
+        }
+        else {
+            (*(*n.prev).inner.get()).next = n.next;
+        }
+        if n.next.is_null() {
+            list.tail = n.prev;
+        }
+        else {
+            (*(*n.next).inner.get()).prev = n.prev;
+        }
+        n.prev = null();
+        n.next = null();
+    }
+
+    /// Removes waiters from the head of the list as long as they're compatible with each other,
+    /// marking them notified and moving their wakers in the given WakeList
+    fn notify_batch(&mut self, batch: &mut Batch, wakers: &mut WakeList) {
+        while !batch.done && !wakers.is_full() {
+            let head = self.list().head;
+            if head.is_null() {
+                batch.done = true;
+                break;
+            }
+            unsafe {
+                let node = &*head;
+                if !batch.admit(node.kind) {
+                    break;
+                }
+                self.unlink(node);
+                self.queue.waiting.fetch_sub(1, Ordering::Relaxed);
+                node.set_state(NOTIFIED);
+                wakers.push((*node.inner.get()).waker.take());
+            }
+        }
+    }
+
+    /// Removes the waiting readers from the list, and the first upgradable reader if `upgradable` is set,
+    /// leaving writers in place, until the end of the list or until the WakeList is full
+    fn notify_readers(&mut self, upgradable: &mut bool, wakers: &mut WakeList) -> bool {
+        let mut current = self.list().head;
+        while !current.is_null() {
+            if wakers.is_full() {
+                return false;
+            }
+            unsafe {
+                let node = &*current;
+                current = (*node.inner.get()).next;
+                match node.kind {
+                    WaitKind::Shared => {},
+                    WaitKind::Upgradable if *upgradable => *upgradable = false,
+                    _ => continue,
+                }
+                self.unlink(node);
+                self.queue.waiting.fetch_sub(1, Ordering::Relaxed);
+                node.set_state(NOTIFIED);
+                wakers.push((*node.inner.get()).waker.take());
+            }
+        }
+        true
+    }
+
+    /// Moves the wakers of the tasks polling without a node in the given WakeList, until it's full
+    fn notify_unpinned(&mut self, wakers: &mut WakeList) -> bool {
+        let unpinned = &mut self.list().unpinned;
+        while !wakers.is_full() {
+            match unpinned.pop() {
+                Some(waker) => {
+                    self.queue.waiting.fetch_sub(1, Ordering::Relaxed);
+                    wakers.push(Some(waker));
+                },
+                None => return true,
+            }
+        }
+        unpinned.is_empty()
+    }
+
+    /// Unregisters a task polling without a node, returns true if it was registered
+    fn forget_unpinned(&mut self, waker: &Waker) -> bool {
+        let unpinned = &mut self.list().unpinned;
+        match unpinned.iter().position(|w| w.will_wake(waker)) {
+            Some(index) => {
+                unpinned.swap_remove(index);
+                self.queue.waiting.fetch_sub(1, Ordering::Relaxed);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Removes the oldest waiter leaving it in the given state, returning its waker
+    fn take_head(&mut self, state: u8) -> Option<Option<Waker>> {
+        let head = self.list().head;
+        if head.is_null() {
+            return None;
+        }
+        unsafe {
+            let node = &*head;
+            self.unlink(node);
+            self.queue.waiting.fetch_sub(1, Ordering::Relaxed);
+            node.set_state(state);
+            Some((*node.inner.get()).waker.take())
+        }
+    }
+}
+
+impl WaiterQueue {
+    pub(crate) const fn new() -> WaiterQueue {
+        WaiterQueue {
+            lock: RawMutex::INIT,
+            waiting: AtomicUsize::new(0),
+            list: UnsafeCell::new(List {
+                head: null(),
+                tail: null(),
+                unpinned: Vec::new(),
+            }),
+            #[cfg(feature = "stats")]
+            stats: WaitStats::new(),
+            #[cfg(any(feature = "tracing", feature = "deadlock_detection", feature = "watchdog"))]
+            name: None,
+            #[cfg(feature = "lockdep")]
+            class: None,
+            #[cfg(feature = "watchdog")]
+            watch: HoldWatch::new(),
+        }
+    }
+
+    /// Creates a queue whose tracing events, deadlock and watchdog reports are tagged with the name of its lock
+    pub(crate) const fn named(name: &'static str) -> WaiterQueue {
+        #[allow(unused_mut)]
+        let mut queue = WaiterQueue::new();
+        #[cfg(any(feature = "tracing", feature = "deadlock_detection", feature = "watchdog"))]
+        {
+            queue.name = Some(name);
+        }
+        #[cfg(not(any(feature = "tracing", feature = "deadlock_detection", feature = "watchdog")))]
+        let _ = name;
+        queue
+    }
+
+    /// Creates a queue whose future acquisitions are validated against the ordering rules of `class`
+    #[cfg(feature = "lockdep")]
+    pub(crate) const fn with_class(class: &'static LockClass) -> WaiterQueue {
+        let mut queue = WaiterQueue::named(class.name());
+        queue.class = Some(class);
+        queue
+    }
+
+    /// Returns the number of futures waiting, or about to
+    #[cfg(feature = "stats")]
+    pub(crate) fn len(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// Traces a poll of a node that has been woken
+    #[cfg(feature = "tracing")]
+    fn polled(&self, node: &WaitNode) {
+        let state = node.state();
+        if state == NOTIFIED || state == GRANTED {
+            tracing::trace!(lock = self.name, kind = node.kind.name(), handed_off = state == GRANTED, "lock waiter woken");
+        }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    fn polled(&self, _node: &WaitNode) {}
+
+    /// Validates the lock order before the first attempt of a located node
+    #[cfg(feature = "lockdep")]
+    fn ordering(&self, node: &WaitNode) {
+        if let (Some(class), Some(site)) = (self.class, node.site) {
+            // an upgrade holds the lock already
+            if node.state() == IDLE && node.kind != WaitKind::Upgrade {
+                crate::lockdep::acquiring(class, site);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "lockdep"))]
+    fn ordering(&self, _node: &WaitNode) {}
+
+    /// Records where the lock has been acquired from, for the lock order validation and the watchdog
+    #[cfg(any(feature = "lockdep", feature = "watchdog"))]
+    fn acquired_at(&self, node: &WaitNode) {
+        if let Some(site) = node.site {
+            // an upgrade doesn't take the lock again, it has been recorded already
+            #[cfg(feature = "lockdep")]
+            {
+                if self.class.is_some() && node.kind != WaitKind::Upgrade {
+                    crate::lockdep::located(self.id(), site);
+                }
+            }
+            #[cfg(feature = "watchdog")]
+            self.watch.located(site);
+        }
+    }
+
+    #[cfg(not(any(feature = "lockdep", feature = "watchdog")))]
+    fn acquired_at(&self, _node: &WaitNode) {}
+
+    /// Lets the watchdog check the current hold, to be called by waiters without holding the list
+    #[cfg(feature = "watchdog")]
+    fn overdue(&self) {
+        self.watch.check(self.name, self.waiting.load(Ordering::Relaxed));
+    }
+
+    #[cfg(not(feature = "watchdog"))]
+    fn overdue(&self) {}
+
+    /// Records the start of a wait, the first time the node fails to acquire
+    #[cfg(any(feature = "stats", feature = "tracing", feature = "deadlock_detection"))]
+    unsafe fn wait_started(&self, node: &WaitNode) {
+        let since = &mut (*node.inner.get()).waiting_since;
+        if since.is_none() {
+            *since = Some(Instant::now());
+            #[cfg(feature = "tracing")]
+            tracing::trace!(lock = self.name, kind = node.kind.name(), "lock wait started");
+            #[cfg(feature = "deadlock_detection")]
+            crate::deadlock::wait_started(node as *const WaitNode as usize, self.id(), self.name);
+        }
+    }
+
+    #[cfg(not(any(feature = "stats", feature = "tracing", feature = "deadlock_detection")))]
+    unsafe fn wait_started(&self, _node: &WaitNode) {}
+
+    /// Records an acquisition, and the end of the wait if the node had to wait
+    #[cfg(any(feature = "stats", feature = "tracing", feature = "deadlock_detection"))]
+    unsafe fn acquired(&self, node: &WaitNode) {
+        let wait = (*node.inner.get()).waiting_since.take().map(|since| since.elapsed());
+        #[cfg(feature = "stats")]
+        {
+            if let Some(wait) = wait {
+                self.stats.waited(wait);
+            }
+        }
+        #[cfg(feature = "deadlock_detection")]
+        {
+            if wait.is_some() {
+                crate::deadlock::wait_ended(node as *const WaitNode as usize);
+            }
+        }
+        #[cfg(feature = "tracing")]
+        tracing::trace!(lock = self.name, kind = node.kind.name(), wait = ?wait, "lock acquired");
+    }
+
+    #[cfg(not(any(feature = "stats", feature = "tracing", feature = "deadlock_detection")))]
+    unsafe fn acquired(&self, _node: &WaitNode) {}
+
+    /// Records the end of a wait given up
+    #[cfg(any(feature = "stats", feature = "tracing", feature = "deadlock_detection"))]
+    unsafe fn cancelled(&self, node: &WaitNode) {
+        if let Some(_since) = (*node.inner.get()).waiting_since.take() {
+            #[cfg(feature = "tracing")]
+            tracing::trace!(lock = self.name, kind = node.kind.name(), wait = ?_since.elapsed(), "lock wait cancelled");
+            #[cfg(feature = "deadlock_detection")]
+            crate::deadlock::wait_ended(node as *const WaitNode as usize);
+        }
+    }
+
+    #[cfg(not(any(feature = "stats", feature = "tracing", feature = "deadlock_detection")))]
+    unsafe fn cancelled(&self, _node: &WaitNode) {}
+
+    /// Identifies the lock the queue is embedded in
+    #[cfg(any(feature = "deadlock_detection", feature = "lockdep"))]
+    fn id(&self) -> usize {
+        self as *const WaiterQueue as usize
+    }
+
+    /// Records an acquisition of the lock, to be called by the raw locks on every successful lock
+    pub(crate) fn locked(&self) {
+        #[cfg(feature = "deadlock_detection")]
+        crate::deadlock::locked(self.id(), self.name);
+        // blocking acquisitions are recorded too, so that their releases don't remove someone else's record
+        #[cfg(feature = "lockdep")]
+        {
+            if let Some(class) = self.class {
+                crate::lockdep::acquired(self.id(), class);
+            }
+        }
+        #[cfg(feature = "watchdog")]
+        self.watch.locked();
+    }
+
+    /// Records the release of the lock, to be called by the raw locks on unlock
+    #[cfg(any(feature = "tracing", feature = "deadlock_detection", feature = "lockdep", feature = "watchdog"))]
+    pub(crate) fn released(&self, kind: WaitKind) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(lock = self.name, kind = kind.name(), "lock released");
+        #[cfg(feature = "deadlock_detection")]
+        crate::deadlock::released(self.id(), kind == WaitKind::Exclusive);
+        #[cfg(feature = "lockdep")]
+        {
+            if self.class.is_some() {
+                crate::lockdep::released(self.id());
+            }
+        }
+        #[cfg(feature = "watchdog")]
+        self.watch.released(self.name, self.waiting.load(Ordering::Relaxed));
+        #[cfg(not(any(feature = "tracing", feature = "deadlock_detection")))]
+        let _ = kind;
+    }
+
+    #[cfg(not(any(feature = "tracing", feature = "deadlock_detection", feature = "lockdep", feature = "watchdog")))]
+    pub(crate) fn released(&self, _kind: WaitKind) {}
+
+    /// Returns true if no future is waiting, or about to
+    pub(crate) fn is_empty(&self) -> bool {
+        self.waiting.load(Ordering::Relaxed) == 0
+    }
+
+    fn guard(&self) -> ListGuard<'_> {
+        self.lock.lock();
+        ListGuard { queue: self }
+    }
+
+    /// Tries to acquire the lock, registering the node to be woken if it fails
+    ///
+    /// # Safety
+    /// node must be pinned, and `cancel` must be called with it before it's dropped
+    pub(crate) unsafe fn poll_acquire<G, F>(&self, node: &WaitNode, cx: &mut Context, mut try_acquire: F) -> Poll<G>
+    where
+        F: FnMut() -> Option<G>,
+    {
+        self.polled(node);
+        self.ordering(node);
+        if node.state() == GRANTED {
+            // the lock has been handed over to us, it's still held so try_acquire must claim it
+            node.set_state(IDLE);
+            (*node.inner.get()).waker = None;
+            CLAIMING.with(|c| c.set(self));
+            let guard = try_acquire();
+            CLAIMING.with(|c| c.set(null()));
+            self.acquired(node);
+            self.acquired_at(node);
+            return Poll::Ready(guard.expect("handed off lock wasn't claimed"));
+        }
+        // a future that isn't queued can just try, it has nothing to lose
+        if node.state() == IDLE {
+            if let Some(guard) = try_acquire() {
+                self.acquired(node);
+                self.acquired_at(node);
+                return Poll::Ready(guard);
+            }
+        }
+        let mut list = self.guard();
+        let queued = node.state() == QUEUED;
+        if !queued {
+            // announce ourselves before the last try, pairs with the fence in wake_up
+            self.waiting.fetch_add(1, Ordering::Relaxed);
+        }
+        fence(Ordering::SeqCst);
+        match try_acquire() {
+            Some(guard) => {
+                // whatever wakeup we had has been consumed
+                if queued {
+                    list.unlink(node);
+                }
+                self.waiting.fetch_sub(1, Ordering::Relaxed);
+                node.set_state(IDLE);
+                (*node.inner.get()).waker = None;
+                self.acquired(node);
+                self.acquired_at(node);
+                Poll::Ready(guard)
+            },
+            None => {
+                // Register Waker so we can notified when we can be polled again,
+                // cloning it only if it changed since the last poll
+                let waker = &mut (*node.inner.get()).waker;
+                match waker {
+                    Some(ref w) if w.will_wake(cx.waker()) => {},
+                    _ => *waker = Some(cx.waker().clone()),
+                }
+                if !queued {
+                    if node.state() == NOTIFIED || node.kind == WaitKind::Upgrade {
+                        // we've been woken but someone else was faster, keep our place in line
+                        list.push_front(node);
+                    }
+                    else {
+                        list.push_back(node);
+                    }
+                }
+                self.wait_started(node);
+                drop(list);
+                self.overdue();
+                Poll::Pending
+            },
+        }
+    }
+
+    /// Tries to acquire the lock, registering the task to be woken if it fails, for callers that can't keep a pinned node
+    ///
+    /// Such tasks don't have a place in line: they're woken at every release until they get the lock,
+    /// and unregistered once they get it. A task that stops polling gets a single spurious wakeup at most.
+    pub(crate) fn poll_acquire_unpinned<G, F>(&self, cx: &mut Context, mut try_acquire: F) -> Poll<G>
+    where
+        F: FnMut() -> Option<G>,
+    {
+        if let Some(guard) = try_acquire() {
+            // we could have registered at a previous poll, but then somebody's waiting
+            if !self.is_empty() {
+                self.guard().forget_unpinned(cx.waker());
+            }
+            return Poll::Ready(guard);
+        }
+        let mut list = self.guard();
+        let registered = list.list().unpinned.iter().any(|w| w.will_wake(cx.waker()));
+        if !registered {
+            // announce ourselves before the last try, pairs with the fence in wake_up
+            self.waiting.fetch_add(1, Ordering::Relaxed);
+            list.list().unpinned.push(cx.waker().clone());
+        }
+        fence(Ordering::SeqCst);
+        match try_acquire() {
+            Some(guard) => {
+                list.forget_unpinned(cx.waker());
+                Poll::Ready(guard)
+            },
+            None => Poll::Pending,
+        }
+    }
+
+    /// Removes the node of a future that's going away, passing on an unused wakeup
+    ///
+    /// Returns true if the lock had been handed over to the node, the caller owns it and must unlock it.
+    ///
+    /// # Safety
+    /// node must have only been registered on this queue
+    pub(crate) unsafe fn cancel(&self, node: &WaitNode) -> bool {
+        // only the owner moves a node out of IDLE, so futures that never waited skip the lock
+        if node.state() == IDLE {
+            return false;
+        }
+        let mut list = self.guard();
+        let state = node.state();
+        if state == QUEUED {
+            list.unlink(node);
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+        }
+        node.set_state(IDLE);
+        (*node.inner.get()).waker = None;
+        self.cancelled(node);
+        drop(list);
+        if state == NOTIFIED {
+            self.wake_up();
+        }
+        state == GRANTED
+    }
+
+    /// Removes the node of a future that's going away, dropping its notification if any
+    ///
+    /// For waiters notified all at once, where there's nobody to pass a notification on to.
+    ///
+    /// # Safety
+    /// node must have only been registered on this queue
+    pub(crate) unsafe fn leave(&self, node: &WaitNode) {
+        if node.state() == IDLE {
+            return;
+        }
+        let mut list = self.guard();
+        if node.state() == QUEUED {
+            list.unlink(node);
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+        }
+        node.set_state(IDLE);
+        (*node.inner.get()).waker = None;
+    }
+
+    /// Wakes every waiting reader, and an upgradable reader if `upgradable` is set, leaving writers waiting,
+    /// to be used when the lock becomes readable without being released (e.g. on downgrade)
+    pub(crate) fn wake_readers(&self, mut upgradable: bool) {
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut wakers = WakeList::new();
+        loop {
+            let done = self.guard().notify_readers(&mut upgradable, &mut wakers);
+            wakers.wake_all();
+            if done {
+                break;
+            }
+        }
+        self.wake_unpinned(&mut wakers);
+    }
+
+    /// Wakes every task polling without a node
+    fn wake_unpinned(&self, wakers: &mut WakeList) {
+        loop {
+            let done = self.guard().notify_unpinned(wakers);
+            wakers.wake_all();
+            if done {
+                break;
+            }
+        }
+    }
+
+    /// Called by a raw lock's try_lock while its inner lock is held,
+    /// returns true if the current thread is claiming a lock handed over by `hand_off`
+    pub(crate) fn claim_handoff(&self) -> bool {
+        CLAIMING.with(|c| std::ptr::eq(c.get(), self))
+    }
+
+    /// Unlocks handing the lock over to the oldest waiting future, if any, instead of releasing it
+    ///
+    /// Nobody else can acquire the lock in the meantime, the woken future gets it via `claim_handoff`.
+    pub(crate) fn hand_off<F>(&self, release: F)
+    where
+        F: FnOnce(),
+    {
+        // pairs with the fence in poll_acquire, like in wake_up
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::Relaxed) == 0 {
+            release();
+            self.wake_up();
+            return;
+        }
+        let mut list = self.guard();
+        match list.take_head(GRANTED) {
+            Some(waker) => {
+                drop(list);
+                if let Some(w) = waker {
+                    w.wake();
+                }
+            },
+            None => {
+                // release while holding the list, anyone registering now will see the lock free
+                release();
+                drop(list);
+                // nobody to hand the lock over to, the tasks polling without a node can race for it
+                self.wake_unpinned(&mut WakeList::new());
+            },
+        }
+    }
+
+    /// Waits for a notification, registering the node at the first call
+    ///
+    /// Unlike `poll_acquire` there's no lock to try, the node leaves the list only through `notify_one` or `notify_all`.
+    ///
+    /// # Safety
+    /// node must be pinned, and `cancel` must be called with it before it's dropped
+    pub(crate) unsafe fn poll_notified(&self, node: &WaitNode, cx: &mut Context) -> Poll<()> {
+        let mut list = self.guard();
+        let waker = &mut (*node.inner.get()).waker;
+        match node.state() {
+            NOTIFIED => {
+                node.set_state(IDLE);
+                *waker = None;
+                Poll::Ready(())
+            },
+            QUEUED => {
+                match waker {
+                    Some(ref w) if w.will_wake(cx.waker()) => {},
+                    _ => *waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            },
+            _ => {
+                *waker = Some(cx.waker().clone());
+                // announce ourselves before the caller checks its condition again, pairs with the fence in notify_all
+                self.waiting.fetch_add(1, Ordering::Relaxed);
+                fence(Ordering::SeqCst);
+                list.push_back(node);
+                Poll::Pending
+            },
+        }
+    }
+
+    /// Waits to be handed something over by `grant`, registering the node at the first call
+    ///
+    /// Returns true once granted, or if `try_acquire` succeeds while nobody else is waiting, so that newcomers
+    /// can't overtake the waiters; returns false if the node has been woken by `notify_all` instead.
+    ///
+    /// # Safety
+    /// node must be pinned, and `cancel` must be called with it before it's dropped
+    pub(crate) unsafe fn poll_grant<F>(&self, node: &WaitNode, cx: &mut Context, try_acquire: F) -> Poll<bool>
+    where
+        F: FnOnce() -> bool,
+    {
+        let mut list = self.guard();
+        let waker = &mut (*node.inner.get()).waker;
+        match node.state() {
+            GRANTED => {
+                node.set_state(IDLE);
+                *waker = None;
+                Poll::Ready(true)
+            },
+            NOTIFIED => {
+                node.set_state(IDLE);
+                *waker = None;
+                Poll::Ready(false)
+            },
+            QUEUED => {
+                match waker {
+                    Some(ref w) if w.will_wake(cx.waker()) => {},
+                    _ => *waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            },
+            _ => {
+                // announce ourselves before trying, pairs with the fence in grant
+                self.waiting.fetch_add(1, Ordering::Relaxed);
+                fence(Ordering::SeqCst);
+                if list.list().head.is_null() && try_acquire() {
+                    self.waiting.fetch_sub(1, Ordering::Relaxed);
+                    return Poll::Ready(true);
+                }
+                *waker = Some(cx.waker().clone());
+                list.push_back(node);
+                Poll::Pending
+            },
+        }
+    }
+
+    /// Hands something over to the oldest waiters, in order, as long as `grant` accepts them
+    pub(crate) fn grant<F>(&self, mut grant: F)
+    where
+        F: FnMut(WaitKind) -> bool,
+    {
+        // pairs with the fence in poll_grant: either we see the waiter, or it sees what we're releasing
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut wakers = WakeList::new();
+        loop {
+            let mut done = true;
+            {
+                let mut list = self.guard();
+                loop {
+                    let head = list.list().head;
+                    if head.is_null() || !grant(unsafe { (*head).kind }) {
+                        break;
+                    }
+                    wakers.push(list.take_head(GRANTED).expect("head just checked"));
+                    if wakers.is_full() {
+                        done = false;
+                        break;
+                    }
+                }
+            }
+            wakers.wake_all();
+            if done {
+                return;
+            }
+        }
+    }
+
+    /// Wakes the oldest waiting future, whatever it's waiting for, returns false if there was none
+    pub(crate) fn notify_one(&self) -> bool {
+        if self.waiting.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        let waker = self.guard().take_head(NOTIFIED);
+        match waker {
+            Some(waker) => {
+                if let Some(w) = waker {
+                    w.wake();
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Wakes every waiting future, whatever they're waiting for, returns how many they were
+    pub(crate) fn notify_all(&self) -> usize {
+        let mut count = 0;
+        // pairs with the fence in poll_grant, like in grant
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::Relaxed) == 0 {
+            return count;
+        }
+        let mut wakers = WakeList::new();
+        loop {
+            {
+                let mut list = self.guard();
+                while !wakers.is_full() {
+                    match list.take_head(NOTIFIED) {
+                        Some(waker) => wakers.push(waker),
+                        None => break,
+                    }
+                }
+            }
+            // a partial batch means the list has been emptied
+            let done = !wakers.is_full();
+            count += wakers.len;
+            wakers.wake_all();
+            if done {
+                return count;
+            }
+        }
+    }
+
+    /// Wakes the oldest waiting future, together with all the following ones that can
+    /// hold the lock at the same time (e.g. a writer alone, or a group of readers)
+    pub(crate) fn wake_up(&self) {
+        // pairs with the fence in poll_acquire: either we see the waiter, or it sees the lock released
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut batch = Batch::default();
+        let mut wakers = WakeList::new();
+        while !batch.done {
+            self.guard().notify_batch(&mut batch, &mut wakers);
+            wakers.wake_all();
+        }
+        self.wake_unpinned(&mut wakers);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Wake, Waker};
+
+    pub(crate) struct WakeCounter(AtomicUsize);
+
+    impl WakeCounter {
+        pub(crate) fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Wake for WakeCounter {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns a Waker that counts how many times it has been woken
+    pub(crate) fn counting_waker() -> (Waker, Arc<WakeCounter>) {
+        let counter = Arc::new(WakeCounter(AtomicUsize::new(0)));
+        (Waker::from(Arc::clone(&counter)), counter)
+    }
+}
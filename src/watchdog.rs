@@ -0,0 +1,248 @@
+// Copyright 2018 Marco Napetti
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Locks held longer than a threshold
+//!
+//! There's no background thread or timer checking the locks, the crate stays runtime-agnostic:
+//! a hold is reported when a waiting Future is polled after the threshold, or else when the lock is released.
+//! A lock held forever without contention is never reported.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::panic::Location;
+use std::ptr::null_mut;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::{const_mutex, Mutex};
+
+static THRESHOLD: AtomicU64 = AtomicU64::new(100_000_000);
+
+/// Sets how long a lock can be held before being reported, 100 milliseconds by default
+pub fn set_threshold(threshold: Duration) {
+    THRESHOLD.store(u64::try_from(threshold.as_nanos()).unwrap_or(u64::MAX), Ordering::Relaxed);
+}
+
+/// Returns how long a lock can be held before being reported
+pub fn threshold() -> Duration {
+    Duration::from_nanos(THRESHOLD.load(Ordering::Relaxed))
+}
+
+fn print_hold(hold: &LongHold) {
+    eprintln!("{}", hold);
+}
+
+static HANDLER: Mutex<fn(&LongHold)> = const_mutex(print_hold as fn(&LongHold));
+
+/// Sets the function called on every lock held too long, by default it prints the report on stderr
+///
+/// The handler is called by the task that finds the lock overdue, either a waiter or the holder releasing it.
+pub fn set_handler(handler: fn(&LongHold)) {
+    *HANDLER.lock() = handler;
+}
+
+/// A lock held longer than the threshold
+#[derive(Clone, Debug)]
+pub struct LongHold {
+    name: Option<&'static str>,
+    site: Option<&'static Location<'static>>,
+    held: Duration,
+    waiters: usize,
+    released: bool,
+}
+
+impl LongHold {
+    /// Returns the name of the lock, if it has one
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Returns where the Future that acquired the lock has been created, unknown for blocking acquisitions
+    pub fn site(&self) -> Option<&'static Location<'static>> {
+        self.site
+    }
+
+    /// Returns how long the lock has been held so far
+    pub fn held(&self) -> Duration {
+        self.held
+    }
+
+    /// Returns how many Futures are waiting for the lock
+    pub fn waiters(&self) -> usize {
+        self.waiters
+    }
+
+    /// Returns true if the hold is over, false if the lock is still held
+    pub fn released(&self) -> bool {
+        self.released
+    }
+}
+
+impl fmt::Display for LongHold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "lock {} ", self.name.unwrap_or("<unnamed>"))?;
+        match self.site {
+            Some(site) => write!(f, "acquired at {} ", site)?,
+            None => write!(f, "acquired while blocking ")?,
+        }
+        write!(f, "{} for {:?}, with {} waiters", if self.released { "held" } else { "still held" }, self.held, self.waiters)
+    }
+}
+
+// nanoseconds since the first call plus one, so zero can mean not held
+fn now() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64 + 1
+}
+
+/// The current hold of a lock, updated by the WaiterQueue
+///
+/// Shared holds are watched together, from the first reader in to the last one out.
+pub(crate) struct HoldWatch {
+    holders: AtomicUsize,
+    since: AtomicU64,
+    site: AtomicPtr<Location<'static>>,
+    reported: AtomicBool,
+}
+
+impl HoldWatch {
+    pub(crate) const fn new() -> HoldWatch {
+        HoldWatch {
+            holders: AtomicUsize::new(0),
+            since: AtomicU64::new(0),
+            site: AtomicPtr::new(null_mut()),
+            reported: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn locked(&self) {
+        if self.holders.fetch_add(1, Ordering::Relaxed) == 0 {
+            self.site.store(null_mut(), Ordering::Relaxed);
+            self.reported.store(false, Ordering::Relaxed);
+            self.since.store(now(), Ordering::Relaxed);
+        }
+    }
+
+    /// Sets where the hold started, for locks acquired by a located Future
+    pub(crate) fn located(&self, site: &'static Location<'static>) {
+        // racing readers could each claim the hold, it's a diagnostic
+        let site = site as *const Location<'static> as *mut Location<'static>;
+        self.site.compare_exchange(null_mut(), site, Ordering::Relaxed, Ordering::Relaxed).ok();
+    }
+
+    fn report(&self, name: Option<&'static str>, since: u64, waiters: usize, released: bool) {
+        let site = self.site.load(Ordering::Relaxed);
+        let hold = LongHold {
+            name,
+            site: unsafe { site.as_ref() },
+            held: Duration::from_nanos(now().saturating_sub(since)),
+            waiters,
+            released,
+        };
+        let handler = *HANDLER.lock();
+        handler(&hold);
+    }
+
+    /// Reports the hold if it's overdue, only the first time
+    pub(crate) fn check(&self, name: Option<&'static str>, waiters: usize) {
+        let since = self.since.load(Ordering::Relaxed);
+        if since != 0 && now().saturating_sub(since) > THRESHOLD.load(Ordering::Relaxed) && !self.reported.swap(true, Ordering::Relaxed) {
+            self.report(name, since, waiters, false);
+        }
+    }
+
+    /// Must be called before actually unlocking, reports the hold if it ends overdue
+    pub(crate) fn released(&self, name: Option<&'static str>, waiters: usize) {
+        if self.holders.fetch_sub(1, Ordering::Relaxed) == 1 {
+            let since = self.since.swap(0, Ordering::Relaxed);
+            if since != 0 && now().saturating_sub(since) > THRESHOLD.load(Ordering::Relaxed) {
+                self.report(name, since, waiters, true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::Mutex as StdMutex;
+    use std::task::{Context, Poll};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::mutex::{named_mutex, FutureLockable};
+    use crate::rwlock::named_rwlock;
+    use crate::wakers::tests::counting_waker;
+
+    use super::{set_handler, set_threshold, LongHold};
+
+    static HOLDS: StdMutex<Vec<LongHold>> = StdMutex::new(Vec::new());
+
+    // the handler and threshold are global, locks of other tests must be filtered out
+    fn holds_of(name: &str) -> Vec<LongHold> {
+        HOLDS.lock().unwrap().iter().filter(|h| h.name() == Some(name)).cloned().collect()
+    }
+
+    fn setup() {
+        set_threshold(Duration::from_millis(20));
+        set_handler(|hold| HOLDS.lock().unwrap().push(hold.clone()));
+    }
+
+    #[test]
+    fn overdue_mutex() {
+        setup();
+        let lock = named_mutex("overdue_mutex", 0);
+        let (waker, _) = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let guard = match Box::pin(lock.future_lock()).as_mut().poll(&mut cx) {
+            Poll::Ready(guard) => guard,
+            Poll::Pending => panic!("lock busy"),
+        };
+        let line = line!() - 4;
+        let mut first = Box::pin(lock.future_lock());
+        let mut second = Box::pin(lock.future_lock());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(holds_of("overdue_mutex").is_empty());
+        sleep(Duration::from_millis(30));
+        // a waiter finds the lock overdue, once
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        drop(guard);
+
+        let holds = holds_of("overdue_mutex");
+        assert_eq!(holds.len(), 2);
+        assert!(!holds[0].released() && holds[1].released());
+        assert_eq!(holds[0].waiters(), 2);
+        assert!(holds[1].held() >= Duration::from_millis(30));
+        let site = holds[1].site().expect("site unknown");
+        assert_eq!((site.file(), site.line()), (file!(), line));
+
+        // a short hold isn't reported
+        assert!(first.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(holds_of("overdue_mutex").len(), 2);
+    }
+
+    #[test]
+    fn overdue_readers() {
+        setup();
+        let lock = named_rwlock("overdue_readers", 0);
+        let first = lock.read();
+        sleep(Duration::from_millis(15));
+        let second = lock.read();
+        drop(first);
+        sleep(Duration::from_millis(15));
+        drop(second);
+
+        // neither reader held the lock long enough, but together they did
+        let holds = holds_of("overdue_readers");
+        assert_eq!(holds.len(), 1);
+        assert!(holds[0].site().is_none());
+        assert!(holds[0].held() >= Duration::from_millis(30));
+    }
+}